@@ -61,6 +61,27 @@ impl QueryApi {
         }
     }
 
+    pub async fn get_nearby_peers(&self) -> ApiResult<Vec<p2p::peer::PeerMetadata>> {
+        match self.send2(query::Request::GetNearbyPeers).await? {
+            query::Response::NearbyPeers(peers) => Ok(peers),
+            _ => Err(()),
+        }
+    }
+
+    pub async fn get_known_peers(&self) -> ApiResult<Vec<crate::known::KnownPeer>> {
+        match self.send2(query::Request::GetKnownPeers).await? {
+            query::Response::KnownPeers(peers) => Ok(peers),
+            _ => Err(()),
+        }
+    }
+
+    pub async fn get_transfers(&self) -> ApiResult<Vec<crate::transfer::TransferManifest>> {
+        match self.send2(query::Request::GetTransfers).await? {
+            query::Response::Transfers(transfers) => Ok(transfers),
+            _ => Err(()),
+        }
+    }
+
     pub async fn get_qrcode(&self) -> ApiResult<crate::node::QrPayload> {
         match self.send2(query::Request::GetSharableQrCode(None)).await? {
             query::Response::SharableQrCode(code) => Ok(code),
@@ -96,12 +117,78 @@ impl CmdApi {
         self.send2(cmd::Request::Pair(payload)).await?.into()
     }
 
+    pub async fn rename_peer(&self, id: PeerId, alias: Option<String>) -> EmptyApiResult {
+        self.send2(cmd::Request::RenamePeer { peer: id, alias })
+            .await?
+            .into()
+    }
+
+    pub async fn remove_peer(&self, id: PeerId) -> EmptyApiResult {
+        self.send2(cmd::Request::RemovePeer(id)).await?.into()
+    }
+
+    pub async fn unpair(&self, id: PeerId) -> EmptyApiResult {
+        self.send2(cmd::Request::Unpair(id)).await?.into()
+    }
+
+    pub async fn request_pairing(&self, id: PeerId) -> EmptyApiResult {
+        self.send2(cmd::Request::RequestPairing(id)).await?.into()
+    }
+
+    pub async fn request_code_pairing(&self, id: PeerId) -> EmptyApiResult {
+        self.send2(cmd::Request::RequestCodePairing(id))
+            .await?
+            .into()
+    }
+
+    pub async fn respond_pairing(&self, id: PeerId, accept: bool) -> EmptyApiResult {
+        self.send2(cmd::Request::RespondPairing {
+            peer: id,
+            accept,
+            code: None,
+        })
+        .await?
+        .into()
+    }
+
+    pub async fn respond_pairing_with_code(&self, id: PeerId, code: String) -> EmptyApiResult {
+        self.send2(cmd::Request::RespondPairing {
+            peer: id,
+            accept: true,
+            code: Some(code),
+        })
+        .await?
+        .into()
+    }
+
     pub async fn send_peer(&self, id: PeerId, req: PeerRequest) -> EmptyApiResult {
         self.send2(cmd::Request::SendPeer { peer: id, req })
             .await?
             .into()
     }
 
+    pub async fn send_text(&self, id: PeerId, text: String) -> EmptyApiResult {
+        self.send_peer(id, PeerRequest::SendText(text)).await
+    }
+
+    pub async fn send_file(&self, id: PeerId, path: std::path::PathBuf) -> EmptyApiResult {
+        self.send_peer(id, PeerRequest::SendFile(path)).await
+    }
+
+    pub async fn send_directory(&self, id: PeerId, path: std::path::PathBuf) -> EmptyApiResult {
+        self.send_peer(id, PeerRequest::SendDirectory(path)).await
+    }
+
+    pub async fn clipboard_changed(&self, text: String) -> EmptyApiResult {
+        self.send2(cmd::Request::ClipboardChanged(text))
+            .await?
+            .into()
+    }
+
+    pub async fn resume_transfer(&self, id: u64) -> EmptyApiResult {
+        self.send2(cmd::Request::ResumeTransfer(id)).await?.into()
+    }
+
     pub async fn ctl_cancel(&self, id: PeerId, session: u64) -> EmptyApiResult {
         self.send2(cmd::Request::Ack {
             peer: id,
@@ -141,11 +228,35 @@ pub mod cmd {
         },
         // qr code json payload
         Pair(crate::node::QrPayload),
+        // give a known peer a name or clear it with None
+        RenamePeer {
+            peer: peer::PeerId,
+            alias: Option<String>,
+        },
+        // forget a known peer without telling it, it can't connect until it's paired again
+        RemovePeer(peer::PeerId),
+        // forget a known peer & tell it to forget this node too if it's connected
+        Unpair(peer::PeerId),
+        // ask a nearby peer to pair over the network
+        RequestPairing(peer::PeerId),
+        // ask a nearby peer to pair over the network, the user has to enter the code shown on this device on the peer
+        RequestCodePairing(peer::PeerId),
+        // accept or reject a pairing request from a nearby peer, a code request is accepted with the entered code
+        RespondPairing {
+            peer: peer::PeerId,
+            accept: bool,
+            #[serde(default)]
+            code: Option<String>,
+        },
         Ack {
             peer: peer::PeerId,
             sid: u64,
             ack: Ack,
         },
+        // continue sending an interrupted file from what the peer saved, the peer has to be discovered again
+        ResumeTransfer(u64),
+        // the host's clipboard changed, it is sent to the connected peers the clipboard is synced with
+        ClipboardChanged(String),
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -165,14 +276,12 @@ pub mod cmd {
     #[derive(Debug, Serialize, Deserialize)]
     pub enum PeerRequest {
         LaunchUri(String),
-    }
-
-    impl Into<proto::CtlRequest> for PeerRequest {
-        fn into(self) -> proto::CtlRequest {
-            match self {
-                PeerRequest::LaunchUri(uri) => proto::CtlRequest::LaunchUri(uri),
-            }
-        }
+        // a short snippet such as a one-time code, an address or a command line
+        SendText(String),
+        // the path of a local file to send
+        SendFile(std::path::PathBuf),
+        // the path of a local directory to send with everything in it
+        SendDirectory(std::path::PathBuf),
     }
 
     impl Into<proto::CtlResponse> for Ack {
@@ -195,14 +304,22 @@ pub mod query {
     pub enum Request {
         GetConf,
         GetDiscoveredPeers,
+        GetNearbyPeers,
         GetSharableQrCode(Option<String>),
+        // the peers this node has paired with
+        GetKnownPeers,
+        // files sent to peers which haven't finished
+        GetTransfers,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub enum Response {
         Conf(conf::NodeConfig),
         DiscoveredPeers(Vec<p2p::peer::PeerMetadata>),
+        NearbyPeers(Vec<p2p::peer::PeerMetadata>),
         SharableQrCode(crate::node::QrPayload),
+        Transfers(Vec<crate::transfer::TransferManifest>),
+        KnownPeers(Vec<crate::known::KnownPeer>),
         // Err,
     }
 }
@@ -215,6 +332,9 @@ pub mod event {
     #[derive(Debug, Serialize, Deserialize)]
     pub enum CoreEvent {
         Discovered(PeerMetadata),
+        NearbyDiscovered(PeerMetadata),
+        Updated(PeerMetadata),
+        Lost(PeerId),
         // AskLaunchUri(PeerId, u64, String),
         // LaunchUri { peer: PeerId, sid: u64, uri: String },
         AppControl {
@@ -226,6 +346,52 @@ pub mod event {
             peer: PeerId,
             status: ControlStatus,
         },
+        PairingRequested {
+            peer: PeerMetadata,
+            code: bool,
+        },
+        // the code to show the user while a code pairing request is pending
+        PairingCode {
+            peer: PeerId,
+            code: String,
+        },
+        Paired(PeerMetadata),
+        // the peer unpaired from this node & was forgotten
+        Unpaired(PeerId),
+        // the secret of a known peer is missing or couldn't be read when the node started, it has to be paired again
+        SecretMissing {
+            peer: PeerId,
+            error: Option<String>,
+        },
+        // the private key of this node's identity was missing when it started so a new identity replaced the one
+        // with the given id, every known peer rejects the new one & has to be paired again
+        IdentityReset(PeerId),
+        PairingFailed(PeerId),
+        // the progress of a file being sent or received, the rate is in bytes per second
+        TransferProgress {
+            peer: PeerId,
+            sid: u64,
+            bytes: u64,
+            total: u64,
+            rate: u64,
+        },
+        // a file was sent or received & its SHA-256 digest matched on both peers
+        TransferCompleted {
+            peer: PeerId,
+            sid: u64,
+            path: std::path::PathBuf,
+            digest: String,
+        },
+        // a file being sent was interrupted, it can be resumed with its transfer id
+        TransferInterrupted {
+            peer: PeerId,
+            id: u64,
+        },
+        // a peer the clipboard is synced with copied something, the host's clipboard should be set to it
+        Clipboard {
+            peer: PeerId,
+            text: String,
+        },
         // PeerCtlWaiting(PeerId),
         // PeerCtlSuccess(PeerId),
         // PeerCtlCancel(PeerId),
@@ -234,15 +400,39 @@ pub mod event {
 
     #[derive(Debug, Serialize, Deserialize)]
     pub enum ControlMessage {
-        LaunchUri { uri: String, ask: bool },
+        LaunchUri {
+            uri: String,
+            ask: bool,
+        },
+        Text {
+            text: String,
+            ask: bool,
+        },
+        ReceiveFile {
+            name: String,
+            size: u64,
+            ask: bool,
+        },
+        ReceiveDirectory {
+            name: String,
+            files: usize,
+            size: u64,
+            ask: bool,
+        },
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub enum ControlStatus {
         Waiting,
+        // the remote peer accepted & the data is being transferred
+        Transferring,
         Success,
         Cancelled,
         Failed,
+        // the data did not match its digest & was discarded
+        Corrupted,
+        // the remote peer doesn't allow the request
+        Blocked,
     }
 }
 
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use futures::{SinkExt, StreamExt};
+use p2p::peer::{Peer, PeerId, PeerMetadata};
+use ring::digest;
+use tokio::{
+    io::{DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc::{self, UnboundedSender},
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, error};
+
+use crate::{
+    node::InternalEvent,
+    proto::{Ctl, CtlRequest, Session, SessionCodec},
+};
+
+/// How many clipboard changes can wait to be sent to a peer before new ones are dropped
+const BACKLOG: usize = 8;
+
+type Reader = FramedRead<ReadHalf<DuplexStream>, SessionCodec>;
+type Writer = FramedWrite<WriteHalf<DuplexStream>, SessionCodec>;
+
+/// The clipboard sessions with remote peers & the last content seen on any side of them
+#[derive(Debug, Default)]
+pub(crate) struct Clipboard {
+    /// the digest of the last content this node sent or received
+    last: Option<digest::Digest>,
+    /// the senders of the long lived sessions with each peer
+    sessions: HashMap<PeerId, mpsc::Sender<String>>,
+}
+
+impl Clipboard {
+    /// Records the content, returns false if it is what was last sent or received.
+    ///
+    /// Setting the clipboard to content received from a peer makes the host app report it as changed, so
+    /// content which was just seen isn't sent back to where it came from.
+    pub fn changed(&mut self, text: &str) -> bool {
+        let d = digest::digest(&digest::SHA256, text.as_bytes());
+        if self.last.as_ref().map(|last| last.as_ref()) == Some(d.as_ref()) {
+            return false;
+        }
+        self.last = Some(d);
+        true
+    }
+
+    /// Sends the content over the session with the peer, starting a new session if there is none
+    pub fn push(&mut self, peer: Peer, sid: u64, text: String) {
+        if let Some(session) = self.sessions.get(&peer.id) {
+            match session.try_send(text) {
+                Ok(()) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    error!("Dropped a clipboard change for peer {}", peer.id);
+                    return;
+                }
+                // the session ended, start a new one
+                Err(mpsc::error::TrySendError::Closed(t)) => return self.start(peer, sid, t),
+            }
+        }
+        self.start(peer, sid, text)
+    }
+
+    /// Ends the session with the peer
+    pub fn stop(&mut self, peer: &PeerId) {
+        self.sessions.remove(peer);
+    }
+
+    fn start(&mut self, peer: Peer, sid: u64, text: String) {
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        _ = tx.try_send(text);
+        self.sessions.insert(peer.id.clone(), tx);
+        tokio::spawn(send(peer, sid, rx));
+    }
+}
+
+/// Sends each clipboard change over a single stream until the node ends the session or the stream fails
+async fn send(peer: Peer, sid: u64, mut rx: mpsc::Receiver<String>) {
+    let stream = match peer.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to open a stream to peer {}: {}", peer.id, e);
+            return;
+        }
+    };
+    let (r, w) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(r, SessionCodec::default());
+    let mut writer = FramedWrite::new(w, SessionCodec::default());
+
+    debug!("Starting clipboard session with peer {}", peer.id);
+    loop {
+        tokio::select! {
+            text = rx.recv() => {
+                let Some(text) = text else { break };
+                let session = Session {
+                    id: sid,
+                    ctl: Ctl::Request(CtlRequest::Clipboard(text)),
+                };
+                if writer.send(session).await.is_err() {
+                    error!("Failed to send clipboard to peer {}", peer.id);
+                    break;
+                }
+            }
+            // the host never responds, the stream only ends with the connection
+            res = reader.next() => {
+                debug!("Clipboard session ended by peer {}: {:?}", peer.id, res);
+                break;
+            }
+        }
+    }
+    debug!("Ending clipboard session with peer {}", peer.id);
+}
+
+/// Forwards each clipboard change a remote peer sends over the stream to the node
+pub(crate) async fn receive(
+    mut reader: Reader,
+    _writer: Writer,
+    meta: PeerMetadata,
+    first: Session,
+    tx: UnboundedSender<InternalEvent>,
+) {
+    debug!("Accepting clipboard session with peer {}", meta.id);
+    let mut next = Some(first);
+    while let Some(session) = next {
+        let Ctl::Request(CtlRequest::Clipboard(text)) = session.ctl else {
+            error!("Unexpected message in clipboard session: {:?}", session.ctl);
+            break;
+        };
+        let event = InternalEvent::ClipboardReceived {
+            peer: meta.id.clone(),
+            text,
+        };
+        if tx.send(event).is_err() {
+            break;
+        }
+        next = match reader.next().await {
+            Some(Ok(session)) => Some(session),
+            _ => None,
+        };
+    }
+    debug!("Ending clipboard session with peer {}", meta.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clipboard;
+
+    #[test]
+    fn echoed_content_is_suppressed() {
+        let mut clipboard = Clipboard::default();
+        assert!(clipboard.changed("hello"));
+        // the host app reports the content it was just given
+        assert!(!clipboard.changed("hello"));
+        assert!(clipboard.changed("world"));
+        assert!(clipboard.changed("hello"));
+    }
+}
@@ -6,8 +6,10 @@ use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
 use std::path;
+use url::Url;
 
 use crate::err;
+use crate::known::KnownPeers;
 use crate::plat;
 use crate::store;
 
@@ -18,17 +20,142 @@ pub struct NodeConfig {
     pub name: String,
     // #[serde(skip)]
     pub id: peer::PeerId,
-    pub known_peers: HashSet<peer::PeerMetadata>,
+    pub known_peers: KnownPeers,
     pub auto_accept: bool,
+    /// where files received from peers are saved
+    #[serde(default = "plat::download_dir")]
+    pub download_dir: path::PathBuf,
+    /// known peers the clipboard is synced with, both peers have to enable each other
+    #[serde(default)]
+    pub clipboard_peers: HashSet<peer::PeerId>,
+    /// open uris which are accepted without asking from core, for hosts without an app to open them
+    #[serde(default)]
+    pub launch_uris: bool,
+    /// the uris peers may launch on this device
+    #[serde(default)]
+    pub uris: UriPolicy,
+    /// how many seconds a discovered peer is kept without a presence response before it is lost, read when the
+    /// node starts
+    #[serde(default = "default_peer_ttl")]
+    pub peer_ttl: u64,
+}
+
+fn default_peer_ttl() -> u64 {
+    10
+}
+
+impl NodeConfig {
+    /// how a kind of request from the peer is handled, a peer without a rule for it follows auto_accept. Files are
+    /// written to disk so they are only accepted without asking when the peer has a rule for it
+    pub fn rule(&self, peer: &peer::PeerId, kind: RequestKind) -> Rule {
+        match self
+            .known_peers
+            .get(peer)
+            .and_then(|known| known.trust.get(&kind))
+        {
+            Some(rule) => *rule,
+            None if self.auto_accept && kind != RequestKind::File => Rule::Accept,
+            None => Rule::Ask,
+        }
+    }
+}
+
+/// The kinds of requests a peer can send, which can each be given a rule
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// launching a uri
+    Uri,
+    /// showing a text snippet
+    Text,
+    /// saving a file or a directory
+    File,
+}
+
+/// How a request from a peer is handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// the user is asked to accept the request
+    Ask,
+    /// the request is accepted without asking the user
+    Accept,
+    /// the request is rejected without asking the user
+    Block,
+}
+
+/// Which uris a peer may launch, checked before the user is asked
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UriPolicy {
+    /// the allowed schemes, compared case insensitively
+    pub schemes: Vec<String>,
+    /// the allowed hosts, `*.example.com` also allows any subdomain of example.com, any host is allowed when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+impl Default for UriPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: vec![String::from("http"), String::from("https")],
+            hosts: Vec::new(),
+        }
+    }
+}
+
+impl UriPolicy {
+    /// Is the uri's scheme allowed & its host matched by one of the patterns.
+    ///
+    /// The host is the one a browser would open, e.g. `https://evil.net\@example.com` opens evil.net, and uris
+    /// with backslashes, whitespace or control characters are never allowed as handlers differ in how they read them.
+    pub fn allows(&self, uri: &str) -> bool {
+        if uri
+            .chars()
+            .any(|c| c == '\\' || c.is_whitespace() || c.is_control())
+        {
+            return false;
+        }
+        let Ok(url) = Url::parse(uri) else {
+            return false;
+        };
+        if !self
+            .schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+        {
+            return false;
+        }
+        if self.hosts.is_empty() {
+            return true;
+        }
+        let Some(host) = url.host_str().map(|h| h.trim_matches(['[', ']'])) else {
+            return false;
+        };
+        self.hosts.iter().any(|pattern| {
+            let pattern = pattern.trim_matches(['[', ']']).to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(domain) => {
+                    host == domain
+                        || host
+                            .strip_suffix(domain)
+                            .is_some_and(|sub| sub.ends_with('.'))
+                }
+                None => host == pattern,
+            }
+        })
+    }
 }
 
 impl Default for NodeConfig {
     fn default() -> Self {
         Self {
             name: plat::host_name(),
-            known_peers: HashSet::new(),
+            known_peers: KnownPeers::default(),
             id: peer::PeerId::default(),
             auto_accept: false,
+            download_dir: plat::download_dir(),
+            clipboard_peers: HashSet::new(),
+            launch_uris: false,
+            uris: UriPolicy::default(),
+            peer_ttl: default_peer_ttl(),
         }
     }
 }
@@ -138,9 +265,9 @@ impl From<path::PathBuf> for NodeConfigStore {
 #[cfg(test)]
 mod tests {
 
-    use p2p::peer::PeerId;
+    use p2p::peer::{PeerId, PeerMetadata};
 
-    use crate::conf::NodeConfig;
+    use crate::conf::{NodeConfig, RequestKind, Rule, UriPolicy};
     // use crate::conf::NodeConfigStore;
     use crate::err::CoreError;
     use crate::secret::mock_store;
@@ -162,4 +289,65 @@ mod tests {
         assert_eq!("override name", conf.name);
         Ok(())
     }
+
+    #[test]
+    pub fn trust_rules() {
+        let phone = PeerId::from_string("a".repeat(40)).unwrap();
+        let office = PeerId::from_string("b".repeat(40)).unwrap();
+        let mut conf = NodeConfig::default();
+        for id in [&phone, &office] {
+            conf.known_peers.paired(PeerMetadata {
+                id: id.clone(),
+                ..Default::default()
+            });
+        }
+        conf.known_peers.get_mut(&phone).unwrap().trust = [
+            (RequestKind::Uri, Rule::Accept),
+            (RequestKind::Text, Rule::Block),
+        ]
+        .into();
+        assert_eq!(Rule::Accept, conf.rule(&phone, RequestKind::Uri));
+        assert_eq!(Rule::Block, conf.rule(&phone, RequestKind::Text));
+        assert_eq!(Rule::Ask, conf.rule(&phone, RequestKind::File));
+        assert_eq!(Rule::Ask, conf.rule(&office, RequestKind::Uri));
+
+        // a rule overrides auto_accept, which doesn't apply to files
+        conf.auto_accept = true;
+        assert_eq!(Rule::Ask, conf.rule(&office, RequestKind::File));
+        assert_eq!(Rule::Accept, conf.rule(&office, RequestKind::Text));
+        assert_eq!(Rule::Block, conf.rule(&phone, RequestKind::Text));
+        conf.known_peers.get_mut(&office).unwrap().trust =
+            [(RequestKind::File, Rule::Accept)].into();
+        assert_eq!(Rule::Accept, conf.rule(&office, RequestKind::File));
+        assert_eq!(Rule::Ask, conf.rule(&phone, RequestKind::File));
+    }
+
+    #[test]
+    pub fn uri_policy() {
+        let policy = UriPolicy::default();
+        assert!(policy.allows("https://example.com/search?q=1"));
+        assert!(policy.allows("HTTP://example.com"));
+        assert!(!policy.allows("file:///etc/passwd"));
+        assert!(!policy.allows("custom-handler://run"));
+        assert!(!policy.allows("no scheme"));
+
+        let policy = UriPolicy {
+            schemes: vec![String::from("https"), String::from("mailto")],
+            hosts: vec![String::from("*.example.com"), String::from("[::1]")],
+        };
+        assert!(policy.allows("https://example.com"));
+        assert!(policy.allows("https://docs.Example.com:8443/a"));
+        assert!(policy.allows("https://user@www.example.com"));
+        assert!(policy.allows("https://[::1]:8080/"));
+        assert!(!policy.allows("https://notexample.com"));
+        assert!(!policy.allows("https://example.com.evil.net"));
+        assert!(!policy.allows("https://example.com@evil.net"));
+        // browsers read a backslash as a slash, so the host is evil.net
+        assert!(!policy.allows("https://evil.net\\@example.com"));
+        assert!(!policy.allows("https://evil.net\\.example.com"));
+        assert!(!policy.allows("https://example.com /x"));
+        assert!(!policy.allows("https://exa\tmple.com"));
+        // a uri without a host is never matched by a host pattern
+        assert!(!policy.allows("mailto:someone@example.com"));
+    }
 }
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use p2p::peer::{PeerId, PeerMetadata};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::conf::{RequestKind, Rule};
+
+/// A peer this node has paired with
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KnownPeer {
+    /// the metadata the peer last announced
+    pub metadata: PeerMetadata,
+    /// the name the user gave the peer, shown instead of the peer's own name
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// when the peer was paired in seconds since the unix epoch, 0 if it was paired before this was recorded
+    #[serde(default)]
+    pub paired_at: u64,
+    /// when the peer was last discovered in seconds since the unix epoch
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    /// how requests from the peer are handled, overriding auto_accept
+    #[serde(default)]
+    pub trust: HashMap<RequestKind, Rule>,
+}
+
+impl KnownPeer {
+    fn new(metadata: PeerMetadata, paired_at: u64) -> Self {
+        Self {
+            metadata,
+            alias: None,
+            paired_at,
+            last_seen: None,
+            trust: HashMap::new(),
+        }
+    }
+
+    /// the alias if the user gave the peer one, otherwise the peer's own name
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.metadata.name)
+    }
+}
+
+/// The peers this node has paired with, keyed by their id
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct KnownPeers(HashMap<PeerId, KnownPeer>);
+
+impl KnownPeers {
+    pub fn get(&self, id: &PeerId) -> Option<&KnownPeer> {
+        self.0.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &PeerId) -> Option<&mut KnownPeer> {
+        self.0.get_mut(id)
+    }
+
+    pub fn contains(&self, id: &PeerId) -> bool {
+        self.0.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KnownPeer> {
+        self.0.values()
+    }
+
+    /// records a newly paired peer, a peer paired again keeps its alias & trust
+    pub fn paired(&mut self, metadata: PeerMetadata) {
+        let now = now();
+        match self.0.get_mut(&metadata.id) {
+            Some(known) => {
+                known.metadata = metadata;
+                known.paired_at = now;
+            }
+            None => {
+                self.0
+                    .insert(metadata.id.clone(), KnownPeer::new(metadata, now));
+            }
+        }
+    }
+
+    /// records that a peer was discovered with the given metadata, returns false if the peer isn't known
+    pub fn seen(&mut self, metadata: &PeerMetadata) -> bool {
+        let Some(known) = self.0.get_mut(&metadata.id) else {
+            return false;
+        };
+        known.metadata = metadata.clone();
+        known.last_seen = Some(now());
+        true
+    }
+
+    /// sets or clears the alias of a peer, returns false if the peer isn't known
+    pub fn rename(&mut self, id: &PeerId, alias: Option<String>) -> bool {
+        let Some(known) = self.0.get_mut(id) else {
+            return false;
+        };
+        known.alias = alias.filter(|a| !a.trim().is_empty());
+        true
+    }
+
+    pub fn remove(&mut self, id: &PeerId) -> Option<KnownPeer> {
+        self.0.remove(id)
+    }
+}
+
+impl<'de> Deserialize<'de> for KnownPeers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        /// known peers used to be stored as a list of their metadata
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Registry(HashMap<PeerId, KnownPeer>),
+            Legacy(Vec<PeerMetadata>),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Registry(peers) => Self(peers),
+            Repr::Legacy(peers) => Self(
+                peers
+                    .into_iter()
+                    .map(|p| (p.id.clone(), KnownPeer::new(p, 0)))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// the current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p::peer::{PeerId, PeerMetadata};
+
+    use crate::conf::{RequestKind, Rule};
+
+    use super::KnownPeers;
+
+    fn metadata(name: &str) -> PeerMetadata {
+        PeerMetadata {
+            name: String::from(name),
+            id: PeerId::from_string("a".repeat(40)).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renamed_peer_is_known_once() {
+        let mut peers = KnownPeers::default();
+        peers.paired(metadata("laptop"));
+        let id = metadata("laptop").id;
+        assert!(peers.rename(&id, Some(String::from("work laptop"))));
+        peers
+            .get_mut(&id)
+            .unwrap()
+            .trust
+            .insert(RequestKind::Uri, Rule::Accept);
+
+        // the peer renames itself & is paired again
+        assert!(peers.seen(&metadata("laptop 2")));
+        peers.paired(metadata("laptop 2"));
+        assert_eq!(1, peers.len());
+        let known = peers.get(&id).unwrap();
+        assert_eq!("laptop 2", known.metadata.name);
+        assert_eq!("work laptop", known.name());
+        assert_eq!(Some(&Rule::Accept), known.trust.get(&RequestKind::Uri));
+        assert!(known.last_seen.is_some());
+
+        assert!(peers.rename(&id, Some(String::from(" "))));
+        assert_eq!("laptop 2", peers.get(&id).unwrap().name());
+        assert!(peers.remove(&id).is_some());
+        assert!(!peers.seen(&metadata("laptop")));
+    }
+
+    #[test]
+    fn legacy_list_is_migrated() {
+        let json = serde_json::to_string(&vec![metadata("phone")]).unwrap();
+        let peers: KnownPeers = serde_json::from_str(&json).unwrap();
+        let known = peers.get(&metadata("phone").id).unwrap();
+        assert_eq!("phone", known.name());
+        assert_eq!(0, known.paired_at);
+
+        // the registry is stored as a map & read back the same
+        let json = serde_json::to_string(&peers).unwrap();
+        let peers: KnownPeers = serde_json::from_str(&json).unwrap();
+        assert_eq!(1, peers.len());
+    }
+}
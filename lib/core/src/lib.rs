@@ -1,6 +1,8 @@
 pub mod api;
+mod clipboard;
 pub mod conf;
 pub mod err;
+pub mod known;
 mod lan;
 pub mod node;
 mod peer;
@@ -8,6 +10,8 @@ pub mod plat;
 pub mod secret;
 mod state;
 mod store;
+pub mod transfer;
+pub mod vault;
 pub use p2p;
 mod disc;
 mod proto;
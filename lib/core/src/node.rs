@@ -1,29 +1,32 @@
 use std::net::{SocketAddr, SocketAddrV4};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::api::event::{ControlMessage, ControlStatus, CoreEvent};
-use crate::proto::{Ctl, CtlRequest, CtlResponse, Session};
+use crate::proto::{Ctl, CtlRequest, CtlResponse, FileInfo, Session};
 use crate::store::Store;
 use crate::{
     api,
     api::{cmd, query},
-    conf, err,
+    conf::{self, RequestKind, Rule},
+    err,
     lan::LanManager,
     plat, secret,
     state::State,
+    transfer::{self, Manifests, PendingTransfer},
 };
 
-use p2p::pairing::PairingAuthenticator;
-use p2p::peer::{Identity, PeerCandidate, PeerId, PeerMetadata};
+use p2p::pairing::{generate_code, PairingAuthenticator};
+use p2p::peer::{Peer, PeerCandidate, PeerId, PeerMetadata};
 use p2p::{
     discovery,
     event::P2pEvent,
     manager::{P2pConfig, P2pManager},
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{debug, error};
 
 pub struct Node {
     /// the node configuration
@@ -32,12 +35,12 @@ pub struct Node {
     /// the node configuration storage
     store: Store<conf::NodeConfig>,
 
-    // the node identity storage
-    identity: Store<Identity>,
-
     /// the node secret storage
     secrets: secret::SecretStore,
 
+    /// the manifests of unfinished file transfers
+    manifests: Manifests,
+
     /// the p2p manager
     p2p: std::sync::Arc<P2pManager>,
 
@@ -77,7 +80,19 @@ pub struct Node {
 }
 
 impl Node {
+    /// initialize a node keeping its secrets in the OS keyring
     pub async fn init(dir: PathBuf) -> Result<(Self, mpsc::Receiver<CoreEvent>), err::CoreError> {
+        Self::init_with(dir, secret::Secrets::Keyring).await
+    }
+
+    /// Initialize a node keeping its secrets in the given backend.
+    ///
+    /// A damaged settings file is restored from its backup, if there is none this fails with an
+    /// `InvalidData` I/O error & leaves the file alone so the app can ask the user what to do.
+    pub async fn init_with(
+        dir: PathBuf,
+        secrets: secret::Secrets,
+    ) -> Result<(Self, mpsc::Receiver<CoreEvent>), err::CoreError> {
         // build node config from disk or create
         std::fs::create_dir_all(&dir)?;
         let mut conf_file = dir.clone();
@@ -85,17 +100,17 @@ impl Node {
         let store: Store<conf::NodeConfig> = conf_file.into();
         let mut conf = store.put()?;
 
-        // build node identity from disk or create
-        let mut id_file = dir;
-        id_file.push("identity.json");
-        let identity: Store<Identity> = id_file.into();
-        let id = identity.put()?;
-        let (cert, _) = id.into_rustls();
-        conf.id = PeerId::from_cert(&cert);
-        store.set(&conf)?;
+        // keep the manifests of unfinished transfers next to the config
+        let manifests = Manifests::new(dir.join("transfers"))?;
 
         // build the secret store
-        let secrets = secret::SecretStore::new(String::from("local")); // TODO: nanoid id random
+        let secrets = secret::SecretStore::new(String::from("local"), secrets.open(&dir)?); // TODO: nanoid id random
+
+        // build node identity from disk or create, its private key is kept with the secrets
+        let (id, replaced) = crate::peer::load_identity(&dir, &secrets)?;
+        let (cert, _) = id.clone().into_rustls();
+        conf.id = PeerId::from_cert(&cert);
+        store.set(&conf)?;
 
         // build lan
         let mut lan = LanManager::new()?;
@@ -103,27 +118,43 @@ impl Node {
 
         // build p2p
         let p2p_conf = P2pConfig {
-            id: conf.id.clone(),
+            identity: id,
             device: plat::DEVICE_TYPE,
             name: conf.name.clone(),
             multicast: SocketAddr::V4(SocketAddrV4::new(discovery::DISCOVERY_MULTICAST, 50692)), // TODO 0 port??
             p2p_addr: SocketAddr::V4(SocketAddrV4::new(local, 0)),
+            idle_timeout: Duration::from_secs(60),
+            max_connections: 16,
+            peer_ttl: Duration::from_secs(conf.peer_ttl),
         };
         let (p2p, p2p_events) = P2pManager::new(p2p_conf).await?;
 
-        // append known peers
-        for p in secrets.to_known(&conf.known_peers) {
-            p2p.add_known_peer(p);
+        let (events, events_rx) = mpsc::channel(64);
+        if let Some(previous) = replaced {
+            _ = events.try_send(CoreEvent::IdentityReset(previous));
         }
 
-        let (events, events_rx) = mpsc::channel(64);
+        // append known peers, a peer without a secret can't connect so the app is told to pair it again
+        for known in conf.known_peers.iter() {
+            let id = known.metadata.id.clone();
+            let error = match secrets.to_candidate(&known.metadata) {
+                Ok(Some(candidate)) => {
+                    p2p.add_known_peer(candidate);
+                    continue;
+                }
+                Ok(None) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            error!("Known peer {} has no usable secret: {:?}", id, error);
+            _ = events.try_send(CoreEvent::SecretMissing { peer: id, error });
+        }
 
         let node = Self {
             conf,
             store,
-            identity,
             p2p,
             secrets,
+            manifests,
             shutdown: CancellationToken::new(),
             // lan,
             state: State::default(),
@@ -199,6 +230,13 @@ impl Node {
             query::Request::GetDiscoveredPeers => {
                 query::Response::DiscoveredPeers(self.p2p.get_discovered_peers())
             }
+            query::Request::GetNearbyPeers => {
+                query::Response::NearbyPeers(self.p2p.get_nearby_peers())
+            }
+            query::Request::GetKnownPeers => {
+                query::Response::KnownPeers(self.conf.known_peers.iter().cloned().collect())
+            }
+            query::Request::GetTransfers => query::Response::Transfers(self.manifests.unfinished()),
             query::Request::GetSharableQrCode(shared_secret) => {
                 // is the optional shared secret is set, that means this is the second stage of pairing 2 devices
                 let secret = match shared_secret {
@@ -231,39 +269,177 @@ impl Node {
                 self.state.discovery_ct = None;
             }
             cmd::Request::SendPeer { peer: id, req } => {
-                // Current state: "similar" to HTTP 1 - 1 request per connection
-                // TODO: save and reuse connections (HTTP 1.1)
+                // Current state: connections are pooled & each request gets its own stream
                 // TODO: support more complex flows, timeouts, etc.
 
-                let peer = self.p2p.connect_to_peer(&id).await?;
+                // check a file can be sent before connecting
+                let ctl = match &req {
+                    cmd::PeerRequest::LaunchUri(uri) => CtlRequest::LaunchUri(uri.clone()),
+                    cmd::PeerRequest::SendText(text) => CtlRequest::SendText(text.clone()),
+                    cmd::PeerRequest::SendFile(path) => {
+                        CtlRequest::SendFile(transfer::file_info(path).await?)
+                    }
+                    cmd::PeerRequest::SendDirectory(path) => {
+                        CtlRequest::SendBundle(transfer::bundle_info(path).await?)
+                    }
+                };
+                let peer = self.connect(&id).await?;
                 let tx = self.internal.0.clone();
                 self.state.session_id += 1; // update the session id
                 let session = Session {
                     id: self.state.session_id,
-                    ctl: Ctl::Request(req.into()),
+                    ctl: Ctl::Request(ctl),
                 };
-                tokio::spawn(crate::peer::client_handler(peer, session, tx));
+                match req {
+                    cmd::PeerRequest::SendFile(path) => {
+                        let manifests = self.manifests.clone();
+                        tokio::spawn(transfer::send(peer, session, path, tx, manifests));
+                    }
+                    cmd::PeerRequest::SendDirectory(path) => {
+                        tokio::spawn(transfer::send_bundle(peer, session, path, tx));
+                    }
+                    _ => {
+                        tokio::spawn(crate::peer::client_handler(peer, session, tx));
+                    }
+                }
+            }
+            cmd::Request::ResumeTransfer(id) => {
+                let manifest = self.manifests.sent(id).get()?;
+                let mut file = transfer::file_info(&manifest.path).await?;
+                if file.size != manifest.size {
+                    let e = "the file changed since the transfer started";
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e).into());
+                }
+                file.id = id;
+                let peer = self.connect(&manifest.peer).await?;
+                let tx = self.internal.0.clone();
+                self.state.session_id += 1;
+                let session = Session {
+                    id: self.state.session_id,
+                    ctl: Ctl::Request(CtlRequest::ResumeFile(file)),
+                };
+                let manifests = self.manifests.clone();
+                tokio::spawn(transfer::send(peer, session, manifest.path, tx, manifests));
+            }
+            cmd::Request::ClipboardChanged(text) => {
+                if !self.state.clipboard.changed(&text) {
+                    return Ok(cmd::Response::Ok);
+                }
+                // only peers which are already connected are synced, the clipboard is never a reason to connect
+                for id in self.conf.clipboard_peers.clone() {
+                    if !self.p2p.is_connected(&id) {
+                        continue;
+                    }
+                    let peer = match self.connect(&id).await {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            error!("Failed to sync the clipboard with {}: {}", id, e);
+                            continue;
+                        }
+                    };
+                    self.state.session_id += 1;
+                    let sid = self.state.session_id;
+                    self.state.clipboard.push(peer, sid, text.clone());
+                }
             }
             cmd::Request::SetConf(mut new) => {
                 new.id = self.conf.id.clone();
+                for id in self.conf.clipboard_peers.difference(&new.clipboard_peers) {
+                    self.state.clipboard.stop(id);
+                }
                 self.store.set(&new)?;
                 self.conf = new;
             }
             cmd::Request::Pair(payload) => {
-                let auth = PairingAuthenticator::new(payload.secret.into_bytes())?;
+                let auth = PairingAuthenticator::new(payload.secret.clone().into_bytes())?;
                 let known = PeerCandidate::new(&payload.peer, auth);
-                self.conf.known_peers.insert(payload.peer);
-                self.store.set(&self.conf)?; // TODO: save new secret to keyring
+                self.remember(payload.peer, &payload.secret)?;
                 self.p2p.add_known_peer(known);
             }
-            cmd::Request::Ack { sid, ack, .. } => {
-                if let Some(s) = self.state.sessions.remove(&sid) {
-                    _ = s
-                        .send(Session {
-                            id: sid,
-                            ctl: Ctl::Response(ack.into()),
-                        })
-                        .await;
+            cmd::Request::RenamePeer { peer, alias } => {
+                if self.conf.known_peers.rename(&peer, alias) {
+                    self.store.set(&self.conf)?;
+                }
+            }
+            cmd::Request::RemovePeer(id) => {
+                self.forget(&id)?;
+                self.p2p.disconnect(&id);
+            }
+            cmd::Request::Unpair(id) => {
+                // the connection has to be taken before the peer is forgotten
+                let peer = match self.p2p.is_connected(&id) {
+                    true => self.connect(&id).await.ok(),
+                    false => None,
+                };
+                self.forget(&id)?;
+                match peer {
+                    Some(peer) => {
+                        self.state.session_id += 1;
+                        let session = Session {
+                            id: self.state.session_id,
+                            ctl: Ctl::Request(CtlRequest::Unpair),
+                        };
+                        let p2p = self.p2p.clone();
+                        tokio::spawn(crate::peer::unpair_handler(peer, session, p2p));
+                    }
+                    None => self.p2p.disconnect(&id),
+                }
+            }
+            cmd::Request::RequestPairing(id) => {
+                // the remote user has to respond so don't block the node waiting for them
+                let p2p = self.p2p.clone();
+                let events = self.events.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = p2p.request_pairing(&id).await {
+                        error!("Failed to pair with {}: {}", id, e);
+                        _ = events.send(CoreEvent::PairingFailed(id)).await;
+                    }
+                });
+            }
+            cmd::Request::RequestCodePairing(id) => {
+                let code = generate_code();
+                _ = self
+                    .events
+                    .send(CoreEvent::PairingCode {
+                        peer: id.clone(),
+                        code: code.clone(),
+                    })
+                    .await;
+                let p2p = self.p2p.clone();
+                let events = self.events.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = p2p.request_pairing_with_code(&id, &code).await {
+                        error!("Failed to pair with {}: {}", id, e);
+                        _ = events.send(CoreEvent::PairingFailed(id)).await;
+                    }
+                });
+            }
+            cmd::Request::RespondPairing { peer, accept, code } => {
+                if let Some(req) = self.state.pairing.remove(&peer) {
+                    match (accept, code) {
+                        (true, Some(code)) => req.accept_with_code(code),
+                        (true, None) => req.accept(),
+                        (false, _) => req.reject(),
+                    }
+                }
+            }
+            cmd::Request::Ack { peer, sid, ack } => {
+                let key = (peer, sid);
+                if let Some(s) = self.state.sessions.remove(&key) {
+                    // an accepted file is saved before responding
+                    match (ack, self.state.transfers.remove(&key)) {
+                        (cmd::Ack::Accepted, Some(transfer)) => {
+                            _ = transfer.accept.send(transfer.dest);
+                        }
+                        (ack, _) => {
+                            _ = s
+                                .send(Session {
+                                    id: sid,
+                                    ctl: Ctl::Response(ack.into()),
+                                })
+                                .await;
+                        }
+                    }
                 }
             }
         }
@@ -273,63 +449,241 @@ impl Node {
     // handle events
     async fn handle_event(&mut self, event: InternalEvent) -> Result<(), err::CoreError> {
         match event {
+            InternalEvent::InboundSession { meta, body, tx } if body.is_unpair() => {
+                // the client closes the connection once it has the response
+                self.forget(&meta.id)?;
+                _ = tx
+                    .send(Session {
+                        id: body.id,
+                        ctl: Ctl::Response(CtlResponse::Success),
+                    })
+                    .await;
+                _ = self.events.send(CoreEvent::Unpaired(meta.id)).await;
+            }
             InternalEvent::InboundSession { meta, body, tx } => {
-                self.state.sessions.insert(body.id, tx.clone());
-                match body.ctl {
+                let kind = match &body.ctl {
+                    Ctl::Request(CtlRequest::LaunchUri(_)) => RequestKind::Uri,
+                    Ctl::Request(CtlRequest::SendText(_)) => RequestKind::Text,
+                    x => {
+                        error!("unhandled app ctl request: {:?}", x);
+                        return Ok(());
+                    }
+                };
+                let rule = self.conf.rule(&meta.id, kind);
+                // a request which isn't allowed never reaches the user
+                let refused = match &body.ctl {
+                    Ctl::Request(CtlRequest::LaunchUri(uri)) if !self.conf.uris.allows(uri) => {
+                        error!("Peer {} sent a uri which isn't allowed: {}", meta.id, uri);
+                        Some(crate::proto::CTL_URI_ERR)
+                    }
+                    _ if rule == Rule::Block => {
+                        debug!("Blocked a {:?} request from peer {}", kind, meta.id);
+                        Some(crate::proto::CTL_BLOCKED)
+                    }
+                    _ => None,
+                };
+                if let Some(code) = refused {
+                    _ = tx
+                        .send(Session {
+                            id: body.id,
+                            ctl: Ctl::Response(CtlResponse::Error(code)),
+                        })
+                        .await;
+                    return Ok(());
+                }
+                let ask = rule == Rule::Ask;
+                let ctl = match body.ctl {
                     Ctl::Request(CtlRequest::LaunchUri(uri)) => {
-                        let response = match self.conf.auto_accept {
-                            true => CtlResponse::Success,
-                            false => CtlResponse::Waiting,
-                        };
-                        let event = CoreEvent::AppControl {
-                            peer: meta.id,
-                            sid: body.id,
-                            ctl: ControlMessage::LaunchUri {
-                                uri,
-                                ask: !self.conf.auto_accept,
-                            },
-                        };
-                        // let msg = match self.conf.auto_accept {
-                        //     true => (
-                        //         event::CoreEvent::LaunchUri(meta.id, body.id, uri),
-                        //         CtlResponse::Success,
-                        //     ),
-                        //     false => (
-                        //         CoreEvent::AskLaunchUri(meta.id, body.id, uri),
-                        //         CtlResponse::Waiting,
-                        //     ),
-                        // };
-                        let res = match self.events.send(event).await {
-                            Err(_) => CtlResponse::Error(crate::proto::CTL_UNKNOWN_ERR),
-                            Ok(()) => response,
-                        };
-                        _ = tx
-                            .send(Session {
-                                id: body.id,
-                                ctl: Ctl::Response(res),
-                            })
-                            .await;
+                        ControlMessage::LaunchUri { uri, ask }
                     }
-                    x => error!("unhandled app ctl request: {:?}", x),
+                    Ctl::Request(CtlRequest::SendText(text)) => ControlMessage::Text { text, ask },
+                    _ => return Ok(()),
+                };
+                self.state
+                    .sessions
+                    .insert((meta.id.clone(), body.id), tx.clone());
+                let mut response = match ask {
+                    false => CtlResponse::Success,
+                    true => CtlResponse::Waiting,
+                };
+                // a host without an app opens the uri from core, the event is only informational
+                let launched = match &ctl {
+                    ControlMessage::LaunchUri { uri, ask: false } if self.conf.launch_uris => {
+                        match plat::open_uri(uri) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error!("Failed to open {}: {}", uri, e);
+                                response = CtlResponse::Error(crate::proto::CTL_UNKNOWN_ERR);
+                                false
+                            }
+                        }
+                    }
+                    _ => false,
+                };
+                let event = CoreEvent::AppControl {
+                    peer: meta.id,
+                    sid: body.id,
+                    ctl,
+                };
+                // let msg = match self.conf.auto_accept {
+                //     true => (
+                //         event::CoreEvent::LaunchUri(meta.id, body.id, uri),
+                //         CtlResponse::Success,
+                //     ),
+                //     false => (
+                //         CoreEvent::AskLaunchUri(meta.id, body.id, uri),
+                //         CtlResponse::Waiting,
+                //     ),
+                // };
+                let res = match self.events.send(event).await {
+                    Err(_) if !launched => CtlResponse::Error(crate::proto::CTL_UNKNOWN_ERR),
+                    _ => response,
+                };
+                _ = tx
+                    .send(Session {
+                        id: body.id,
+                        ctl: Ctl::Response(res),
+                    })
+                    .await;
+            }
+            InternalEvent::InboundTransfer {
+                meta,
+                sid,
+                file,
+                bundle,
+                tx,
+                accept,
+            } => {
+                // only the file's name is used so the peer can't write outside of the download directory
+                let Some(name) = Path::new(&file.name).file_name() else {
+                    error!("Peer {} sent a file without a name", meta.id);
+                    _ = tx
+                        .send(Session {
+                            id: sid,
+                            ctl: Ctl::Response(CtlResponse::Error(crate::proto::CTL_IO_ERR)),
+                        })
+                        .await;
+                    return Ok(());
+                };
+                let rule = self.conf.rule(&meta.id, RequestKind::File);
+                if rule == Rule::Block {
+                    debug!("Blocked a file from peer {}", meta.id);
+                    _ = tx
+                        .send(Session {
+                            id: sid,
+                            ctl: Ctl::Response(CtlResponse::Error(crate::proto::CTL_BLOCKED)),
+                        })
+                        .await;
+                    return Ok(());
+                }
+                let dest = self.conf.download_dir.join(name);
+                let event = CoreEvent::AppControl {
+                    peer: meta.id.clone(),
+                    sid,
+                    ctl: match bundle {
+                        Some(files) => ControlMessage::ReceiveDirectory {
+                            name: file.name,
+                            files,
+                            size: file.size,
+                            ask: rule == Rule::Ask,
+                        },
+                        None => ControlMessage::ReceiveFile {
+                            name: file.name,
+                            size: file.size,
+                            ask: rule == Rule::Ask,
+                        },
+                    },
+                };
+                if self.events.send(event).await.is_err() {
+                    _ = tx
+                        .send(Session {
+                            id: sid,
+                            ctl: Ctl::Response(CtlResponse::Error(crate::proto::CTL_UNKNOWN_ERR)),
+                        })
+                        .await;
+                } else if rule == Rule::Accept {
+                    _ = accept.send(dest);
+                } else {
+                    self.state
+                        .sessions
+                        .insert((meta.id.clone(), sid), tx.clone());
+                    self.state
+                        .transfers
+                        .insert((meta.id, sid), PendingTransfer { dest, accept });
+                    _ = tx
+                        .send(Session {
+                            id: sid,
+                            ctl: Ctl::Response(CtlResponse::Waiting),
+                        })
+                        .await;
                 }
             }
+            InternalEvent::TransferProgress {
+                peer,
+                sid,
+                bytes,
+                total,
+                rate,
+            } => {
+                let event = CoreEvent::TransferProgress {
+                    peer,
+                    sid,
+                    bytes,
+                    total,
+                    rate,
+                };
+                _ = self.events.send(event).await;
+            }
+            InternalEvent::TransferCompleted {
+                peer,
+                sid,
+                path,
+                digest,
+            } => {
+                let event = CoreEvent::TransferCompleted {
+                    peer,
+                    sid,
+                    path,
+                    digest,
+                };
+                _ = self.events.send(event).await;
+            }
+            InternalEvent::TransferInterrupted { peer, id } => {
+                _ = self
+                    .events
+                    .send(CoreEvent::TransferInterrupted { peer, id })
+                    .await;
+            }
             InternalEvent::SessionResult { id, body } => match body.ctl {
                 Ctl::Response(res) => {
                     let status = match res {
+                        CtlResponse::Error(crate::proto::CTL_DIGEST_ERR) => {
+                            error!("Failed to perform app control: data did not match its digest");
+                            ControlStatus::Corrupted
+                        }
+                        CtlResponse::Error(crate::proto::CTL_URI_ERR) => {
+                            error!("Failed to perform app control: the uri isn't allowed");
+                            ControlStatus::Blocked
+                        }
+                        CtlResponse::Error(crate::proto::CTL_BLOCKED) => {
+                            error!("Failed to perform app control: the peer blocked the request");
+                            ControlStatus::Blocked
+                        }
                         CtlResponse::Error(code) => {
                             error!("Failed to perform app control: {}", code);
-                            self.state.sessions.get(&body.id); // drop
+                            self.state.sessions.get(&(id.clone(), body.id)); // drop
                             ControlStatus::Failed
                         }
                         CtlResponse::Success => {
-                            self.state.sessions.get(&body.id); // drop
+                            self.state.sessions.get(&(id.clone(), body.id)); // drop
                             ControlStatus::Success
                         }
                         CtlResponse::Cancel => {
-                            self.state.sessions.get(&body.id); // drop
+                            self.state.sessions.get(&(id.clone(), body.id)); // drop
                             ControlStatus::Cancelled
                         }
                         CtlResponse::Waiting => ControlStatus::Waiting,
+                        CtlResponse::Ready | CtlResponse::Resume(_) => ControlStatus::Transferring,
                     };
                     _ = self
                         .events
@@ -338,23 +692,101 @@ impl Node {
                 }
                 x => error!("Unhandled app ctl response {:?}", x),
             },
+            InternalEvent::ClipboardReceived { peer, text } => {
+                if !self.conf.clipboard_peers.contains(&peer) {
+                    debug!("Ignoring the clipboard of {}, syncing isn't enabled", peer);
+                } else if self.state.clipboard.changed(&text) {
+                    _ = self.events.send(CoreEvent::Clipboard { peer, text }).await;
+                }
+            }
             InternalEvent::RequestPresence => self.p2p.request_presence(),
         }
 
         Ok(())
     }
 
+    /// store a paired peer's secret & record the peer in the config, neither is kept if the other can't be stored
+    fn remember(&mut self, peer: PeerMetadata, secret: &String) -> Result<(), err::CoreError> {
+        let previous = self.secrets.get_totp(&peer.id).ok().flatten();
+        self.secrets.set_totp(&peer.id, secret)?;
+        let known = self.conf.known_peers.clone();
+        self.conf.known_peers.paired(peer.clone());
+        if let Err(e) = self.store.set(&self.conf) {
+            self.conf.known_peers = known;
+            _ = match previous {
+                Some(previous) => self.secrets.set_totp(&peer.id, &previous),
+                None => self.secrets.remove_totp(&peer.id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// forget everything about a known peer so it can't connect until it's paired again
+    fn forget(&mut self, id: &PeerId) -> Result<(), err::CoreError> {
+        self.p2p.remove_known_peer(id);
+        self.state.clipboard.stop(id);
+        self.secrets.remove_totp(id)?;
+        let known = self.conf.known_peers.remove(id).is_some();
+        let synced = self.conf.clipboard_peers.remove(id);
+        if known || synced {
+            self.store.set(&self.conf)?;
+        }
+        Ok(())
+    }
+
+    /// connect to a peer, accepting the streams the remote peer opens over a new connection
+    async fn connect(&self, id: &PeerId) -> Result<Peer, err::CoreError> {
+        let connected = self.p2p.is_connected(id);
+        let peer = self.p2p.connect_to_peer(id).await?;
+        if !connected {
+            let tx = self.internal.0.clone();
+            let manifests = self.manifests.clone();
+            tokio::spawn(crate::peer::server_handler(peer.clone(), tx, manifests));
+        }
+        Ok(peer)
+    }
+
     // handle p2p events
     async fn handle_p2p(&mut self, event: P2pEvent) -> Result<(), err::CoreError> {
         match event {
             P2pEvent::PeerDiscovered(peer) => {
+                if self.conf.known_peers.seen(&peer) {
+                    self.store.set(&self.conf)?;
+                }
                 _ = self.events.send(CoreEvent::Discovered(peer)).await
             }
-            P2pEvent::PeerDisconnected(_) => {}
+            P2pEvent::NearbyDiscovered(peer) => {
+                _ = self.events.send(CoreEvent::NearbyDiscovered(peer)).await
+            }
+            P2pEvent::PairingRequested(req) => {
+                let (peer, code) = (req.peer.clone(), req.code);
+                self.state.pairing.insert(peer.id.clone(), req);
+                _ = self
+                    .events
+                    .send(CoreEvent::PairingRequested { peer, code })
+                    .await
+            }
+            P2pEvent::PeerPaired { peer, secret } => {
+                self.remember(peer.clone(), &secret)?;
+                _ = self.events.send(CoreEvent::Paired(peer)).await
+            }
+            P2pEvent::PeerUpdated(peer) => {
+                // keep the stored metadata of a known peer up to date, p2p only moves its address once it was reached there
+                if self.conf.known_peers.seen(&peer) {
+                    self.store.set(&self.conf)?;
+                }
+                _ = self.events.send(CoreEvent::Updated(peer)).await
+            }
+            P2pEvent::PeerLost(id) => {
+                _ = self.events.send(CoreEvent::Lost(id)).await
+            }
+            P2pEvent::PeerDisconnected(id) => self.state.clipboard.stop(&id),
             P2pEvent::PeerConnected(peer) => {
                 // not sending to UI
                 let tx = self.internal.0.clone();
-                tokio::spawn(crate::peer::server_handler(peer, tx));
+                let manifests = self.manifests.clone();
+                tokio::spawn(crate::peer::server_handler(peer, tx, manifests));
             }
         }
 
@@ -384,5 +816,107 @@ pub(crate) enum InternalEvent {
         id: PeerId,
         body: Session,
     },
+    /// A remote client wants to send a file
+    InboundTransfer {
+        /// the peer's metadata
+        meta: PeerMetadata,
+        /// the session id
+        sid: u64,
+        /// the file being sent, or the name & size of a directory
+        file: FileInfo,
+        /// the number of files when a directory is sent
+        bundle: Option<usize>,
+        // the channel for session responses
+        tx: mpsc::Sender<Session>,
+        // the channel to accept the file with its destination
+        accept: oneshot::Sender<PathBuf>,
+    },
+    /// A file transfer made progress
+    TransferProgress {
+        peer: PeerId,
+        sid: u64,
+        bytes: u64,
+        total: u64,
+        rate: u64,
+    },
+    /// A file was sent or received & its digest matched
+    TransferCompleted {
+        peer: PeerId,
+        sid: u64,
+        path: PathBuf,
+        digest: String,
+    },
+    /// A file being sent was interrupted before the remote peer saved it
+    TransferInterrupted {
+        peer: PeerId,
+        id: u64,
+    },
+    /// A remote peer's clipboard changed
+    ClipboardReceived {
+        peer: PeerId,
+        text: String,
+    },
     RequestPresence,
 }
+
+#[cfg(test)]
+mod tests {
+    use p2p::peer::PeerId;
+    use tokio::sync::mpsc;
+
+    use super::Node;
+    use crate::{
+        api::{cmd, event::CoreEvent},
+        proto::{Ctl, CtlResponse, Session},
+        secret::Secrets,
+        vault::VaultKey,
+    };
+
+    #[tokio::test]
+    async fn lost_private_key_is_reported() {
+        let dir = std::env::temp_dir().join("flydrop-node").join("reset");
+        _ = std::fs::remove_dir_all(&dir);
+        let secrets = || Secrets::Vault(VaultKey::Passphrase(String::from("correct horse")));
+        let (node, mut events) = Node::init_with(dir.clone(), secrets()).await.unwrap();
+        let id = node.conf.id.clone();
+        assert!(events.try_recv().is_err());
+        drop(node);
+
+        std::fs::remove_file(dir.join(crate::vault::VAULT_NAME)).unwrap();
+        let (node, mut events) = Node::init_with(dir.clone(), secrets()).await.unwrap();
+        assert_ne!(id, node.conf.id);
+        assert!(matches!(events.try_recv(), Ok(CoreEvent::IdentityReset(p)) if p == id));
+    }
+
+    #[tokio::test]
+    async fn ack_reaches_the_session_of_its_peer() {
+        let dir = std::env::temp_dir().join("flydrop-node").join("ack");
+        _ = std::fs::remove_dir_all(&dir);
+        let secrets = Secrets::Vault(VaultKey::Passphrase(String::from("correct horse")));
+        let (mut node, _events) = Node::init_with(dir, secrets).await.unwrap();
+
+        // both peers chose the same session id
+        let a = PeerId::from_string("a".repeat(40)).unwrap();
+        let b = PeerId::from_string("b".repeat(40)).unwrap();
+        let (tx_a, mut rx_a) = mpsc::channel(1);
+        let (tx_b, mut rx_b) = mpsc::channel(1);
+        node.state.sessions.insert((a.clone(), 1), tx_a);
+        node.state.sessions.insert((b.clone(), 1), tx_b);
+
+        let ack = cmd::Request::Ack {
+            peer: b,
+            sid: 1,
+            ack: cmd::Ack::Cancelled,
+        };
+        node.handle_command(ack).await.unwrap();
+        assert!(matches!(
+            rx_b.try_recv(),
+            Ok(Session {
+                id: 1,
+                ctl: Ctl::Response(CtlResponse::Cancel)
+            })
+        ));
+        assert!(rx_a.try_recv().is_err());
+        assert!(node.state.sessions.contains_key(&(a, 1)));
+    }
+}
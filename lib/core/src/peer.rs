@@ -1,14 +1,25 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
 use futures::{SinkExt, StreamExt};
-use p2p::peer::Peer;
-use tokio::sync::mpsc::{self, UnboundedSender};
+use p2p::{
+    manager::P2pManager,
+    peer::{Identity, Peer, PeerId, PeerMetadata},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::DuplexStream,
+    sync::mpsc::{self, UnboundedSender},
+};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, error};
 
 use crate::{
-    err,
+    clipboard, err,
     node::InternalEvent,
-    proto::{Session, SessionCodec},
-    store,
+    proto::{Ctl, CtlRequest, Session, SessionCodec},
+    secret::SecretStore,
+    store::{self, Store},
+    transfer::{self, Manifests},
 };
 
 impl store::Persistable for p2p::peer::Identity {
@@ -31,8 +42,85 @@ impl store::Persistable for p2p::peer::Identity {
     }
 }
 
+/// The public certificate of the node's identity, its private key is kept in the secret store
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Certificate {
+    certificate: Vec<u8>,
+}
+
+impl store::Persistable for Certificate {
+    type Error = err::CoreError;
+
+    fn read<R>(r: R) -> Result<Self, Self::Error>
+    where
+        R: std::io::Read,
+    {
+        Ok(serde_json::from_reader(r)?)
+    }
+
+    fn write<W>(&self, w: &mut W) -> Result<(), Self::Error>
+    where
+        W: std::io::Write,
+    {
+        let json = serde_json::to_string(self)?;
+        w.write_all(json.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Load the node's identity from its certificate on disk & its private key in the secret store, or create one.
+///
+/// An identity.json holding both halves is migrated, the private key is moved to the secret store before the file is
+/// removed so a crash can't lose it. If the private key of the certificate is missing a new identity is created & the
+/// id of the one it replaced is returned with it, so the app can tell the user why paired peers reject this node.
+pub(crate) fn load_identity(
+    dir: &Path,
+    secrets: &SecretStore,
+) -> Result<(Identity, Option<PeerId>), err::CoreError> {
+    let certs: Store<Certificate> = dir.join("certificate.json").into();
+    let legacy: Store<Identity> = dir.join("identity.json").into();
+
+    if let Ok(identity) = legacy.get() {
+        let (certificate, key) = identity.to_raw();
+        secrets.set_private_key(&PeerId::from_der(&certificate), &key)?;
+        certs.set(&Certificate { certificate })?;
+        legacy.remove()?;
+        debug!("Moved the private key out of identity.json");
+        return Ok((identity, None));
+    }
+
+    let mut replaced = None;
+    if let Ok(Certificate { certificate }) = certs.get() {
+        let id = PeerId::from_der(&certificate);
+        match secrets.get_private_key(&id)? {
+            Some(key) => return Ok((Identity::from_raw(certificate, key), None)),
+            // peers can't authenticate a new identity so they have to be paired again
+            None => {
+                error!(
+                    "The private key of {} is missing, creating a new identity",
+                    id
+                );
+                replaced = Some(id);
+            }
+        }
+    }
+
+    let identity = Identity::default();
+    let (certificate, key) = identity.to_raw();
+    secrets.set_private_key(&PeerId::from_der(&certificate), &key)?;
+    certs.set(&Certificate { certificate })?;
+    Ok((identity, replaced))
+}
+
 pub(crate) async fn client_handler(peer: Peer, req: Session, tx: UnboundedSender<InternalEvent>) {
-    let (r, w) = tokio::io::split(peer.conn);
+    let stream = match peer.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to open a stream to peer {}: {}", peer.id, e);
+            return;
+        }
+    };
+    let (r, w) = tokio::io::split(stream);
     let mut reader = FramedRead::new(r, SessionCodec::default());
     let mut writer = FramedWrite::new(w, SessionCodec::default());
 
@@ -57,17 +145,74 @@ pub(crate) async fn client_handler(peer: Peer, req: Session, tx: UnboundedSender
     debug!("Ending session as client with peer {}", peer.metadata.id);
 }
 
-pub(crate) async fn server_handler(peer: Peer, tx: UnboundedSender<InternalEvent>) {
-    let (r, w) = tokio::io::split(peer.conn);
+/// How long an unpaired peer has to acknowledge the notice before the connection is closed
+const UNPAIR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tells the peer it was unpaired, then closes the connection whether the peer acknowledged it or not
+pub(crate) async fn unpair_handler(peer: Peer, req: Session, p2p: Arc<P2pManager>) {
+    let notice = async {
+        let stream = peer.open_stream().await?;
+        let (r, w) = tokio::io::split(stream);
+        let mut reader = FramedRead::new(r, SessionCodec::default());
+        let mut writer = FramedWrite::new(w, SessionCodec::default());
+        writer.send(req).await?;
+        _ = reader.next().await;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+    };
+    match tokio::time::timeout(UNPAIR_TIMEOUT, notice).await {
+        Ok(Ok(())) => debug!("Peer {} was told it was unpaired", peer.id),
+        Ok(Err(e)) => debug!("Failed to tell peer {} it was unpaired: {}", peer.id, e),
+        Err(_) => debug!("Peer {} didn't acknowledge it was unpaired", peer.id),
+    }
+    p2p.disconnect(&peer.id);
+}
+
+pub(crate) async fn server_handler(
+    peer: Peer,
+    tx: UnboundedSender<InternalEvent>,
+    manifests: Manifests,
+) {
+    while let Some(stream) = peer.accept_stream().await {
+        tokio::spawn(stream_handler(
+            stream,
+            peer.metadata.clone(),
+            tx.clone(),
+            manifests.clone(),
+        ));
+    }
+    debug!("Peer {} disconnected", peer.metadata.id);
+}
+
+async fn stream_handler(
+    stream: DuplexStream,
+    meta: PeerMetadata,
+    tx: UnboundedSender<InternalEvent>,
+    manifests: Manifests,
+) {
+    let (r, w) = tokio::io::split(stream);
     let mut reader = FramedRead::new(r, SessionCodec::default());
     let mut writer = FramedWrite::new(w, SessionCodec::default());
 
     while let Some(Ok(session)) = reader.next().await {
-        debug!("Accepting session as server with peer {}", peer.metadata.id);
+        // the file's bytes follow the request so the transfer takes over the stream
+        if let Ctl::Request(CtlRequest::SendFile(_) | CtlRequest::ResumeFile(_)) = &session.ctl {
+            transfer::receive(reader, writer, meta, session, tx, manifests).await;
+            return;
+        }
+        if let Ctl::Request(CtlRequest::SendBundle(_)) = &session.ctl {
+            transfer::receive_bundle(reader, writer, meta, session, tx).await;
+            return;
+        }
+        if let Ctl::Request(CtlRequest::Clipboard(_)) = &session.ctl {
+            clipboard::receive(reader, writer, meta, session, tx).await;
+            return;
+        }
+
+        debug!("Accepting session as server with peer {}", meta.id);
         let mut mpsc = mpsc::channel(64);
         if tx
             .send(InternalEvent::InboundSession {
-                meta: peer.metadata.clone(),
+                meta: meta.clone(),
                 body: session,
                 tx: mpsc.0,
             })
@@ -84,6 +229,55 @@ pub(crate) async fn server_handler(peer: Peer, tx: UnboundedSender<InternalEvent
             }
         }
 
-        debug!("Ending session as server with peer {}", peer.metadata.id);
+        debug!("Ending session as server with peer {}", meta.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p::peer::{Identity, PeerId};
+
+    use crate::{
+        secret::SecretStore,
+        store::Store,
+        vault::{Vault, VaultKey},
+    };
+
+    use super::load_identity;
+
+    #[test]
+    fn identity_json_is_migrated() {
+        let dir = std::env::temp_dir().join("flydrop-identity");
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = VaultKey::Passphrase(String::from("correct horse"));
+        let vault = Vault::open(dir.join("secrets.vault"), key).unwrap();
+        let secrets = SecretStore::new(String::from("local"), Box::new(vault));
+
+        let legacy: Store<Identity> = dir.join("identity.json").into();
+        let identity = Identity::default();
+        legacy.set(&identity).unwrap();
+
+        let (migrated, replaced) = load_identity(&dir, &secrets).unwrap();
+        assert_eq!(None, replaced);
+        assert_eq!(identity.to_raw(), migrated.to_raw());
+        assert!(!dir.join("identity.json").exists());
+        // only the certificate is left on disk
+        let (_, private_key) = identity.to_raw();
+        let cert = std::fs::read_to_string(dir.join("certificate.json")).unwrap();
+        assert!(!cert.contains(&format!("{:?}", private_key).replace(' ', "")));
+
+        let (loaded, replaced) = load_identity(&dir, &secrets).unwrap();
+        assert_eq!(identity.to_raw(), loaded.to_raw());
+        assert_eq!(None, replaced);
+
+        // the secrets were lost, e.g. the data directory was copied to another host
+        let key = VaultKey::Passphrase(String::from("correct horse"));
+        let vault = Vault::open(dir.join("other.vault"), key).unwrap();
+        let secrets = SecretStore::new(String::from("local"), Box::new(vault));
+        let (reset, replaced) = load_identity(&dir, &secrets).unwrap();
+        assert_ne!(identity.to_raw(), reset.to_raw());
+        let (certificate, _) = identity.to_raw();
+        assert_eq!(Some(PeerId::from_der(&certificate)), replaced);
     }
 }
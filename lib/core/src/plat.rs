@@ -1,7 +1,16 @@
+use std::io;
+use std::path::PathBuf;
+
 use p2p::peer;
 
+#[cfg(target_os = "android")]
+use android as plat;
 #[cfg(target_os = "ios")]
 use ios as plat;
+#[cfg(target_os = "linux")]
+use linux as plat;
+#[cfg(target_os = "macos")]
+use macos as plat;
 #[cfg(target_os = "windows")]
 use win as plat;
 
@@ -13,16 +22,106 @@ pub(crate) fn host_name() -> String {
         .unwrap_or_else(|_| String::from("my-flydrop"))
 }
 
+/// the directory received files are saved to unless the user picks another
+pub(crate) fn download_dir() -> PathBuf {
+    plat::download_dir().unwrap_or_else(std::env::temp_dir)
+}
+
+/// open a uri with the default handler of the host, on mobile the app has to open it
+pub(crate) fn open_uri(uri: &str) -> io::Result<()> {
+    plat::open_uri(uri)
+}
+
+/// the Downloads directory in the user's home directory
+#[allow(dead_code)]
+fn home_downloads(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var).map(|home| PathBuf::from(home).join("Downloads"))
+}
+
 #[cfg(target_os = "windows")]
 mod win {
+    use std::{io, path::PathBuf};
+
     use p2p::peer;
 
     pub const DEVICE_TYPE: peer::DeviceType = peer::DeviceType::WindowsLaptop;
+
+    pub fn download_dir() -> Option<PathBuf> {
+        super::home_downloads("USERPROFILE")
+    }
+
+    pub fn open_uri(uri: &str) -> io::Result<()> {
+        open::that(uri)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{io, path::PathBuf};
+
+    use p2p::peer;
+
+    pub const DEVICE_TYPE: peer::DeviceType = peer::DeviceType::LinuxDevice;
+
+    pub fn download_dir() -> Option<PathBuf> {
+        super::home_downloads("HOME")
+    }
+
+    /// opens the uri with xdg-open or whichever opener is installed, which fails on a host without a desktop
+    pub fn open_uri(uri: &str) -> io::Result<()> {
+        open::that(uri)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::{io, path::PathBuf};
+
+    use p2p::peer;
+
+    pub const DEVICE_TYPE: peer::DeviceType = peer::DeviceType::AppleMac;
+
+    pub fn download_dir() -> Option<PathBuf> {
+        super::home_downloads("HOME")
+    }
+
+    pub fn open_uri(uri: &str) -> io::Result<()> {
+        open::that(uri)
+    }
 }
 
 #[cfg(target_os = "ios")]
 mod ios {
+    use std::{io, path::PathBuf};
+
     use p2p::peer;
 
     pub const DEVICE_TYPE: peer::DeviceType = peer::DeviceType::AppleiPhone;
+
+    /// the app's documents directory, which the user can browse in the Files app
+    pub fn download_dir() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Documents"))
+    }
+
+    pub fn open_uri(_uri: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(target_os = "android")]
+mod android {
+    use std::{io, path::PathBuf};
+
+    use p2p::peer;
+
+    pub const DEVICE_TYPE: peer::DeviceType = peer::DeviceType::AndroidDevice;
+
+    /// the shared Download directory of the primary storage
+    pub fn download_dir() -> Option<PathBuf> {
+        Some(PathBuf::from("/storage/emulated/0/Download"))
+    }
+
+    pub fn open_uri(_uri: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
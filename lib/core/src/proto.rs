@@ -5,6 +5,18 @@ use tokio_util::codec::{Decoder, Encoder, LinesCodec};
 pub const CTL_UNKNOWN_ERR: u32 = 1;
 /// CTL message was declined by user
 pub const CTL_CANCEL: u32 = 2;
+/// CTL message failed reading or writing a file
+pub const CTL_IO_ERR: u32 = 3;
+/// CTL message referred to a transfer the host doesn't know about
+pub const CTL_NOT_FOUND: u32 = 4;
+/// CTL message carried data which did not match its digest
+pub const CTL_DIGEST_ERR: u32 = 5;
+/// CTL message carried a path outside of the directory it is saved to
+pub const CTL_PATH_ERR: u32 = 6;
+/// CTL message carried a uri the host doesn't allow
+pub const CTL_URI_ERR: u32 = 7;
+/// CTL message was of a kind the host's user blocked from the client
+pub const CTL_BLOCKED: u32 = 8;
 
 /// These messages are sent across during an active session between two connected and authenticated devices.
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +27,13 @@ pub struct Session {
     pub ctl: Ctl,
 }
 
+impl Session {
+    /// is this a notice that the client unpaired from the host
+    pub fn is_unpair(&self) -> bool {
+        matches!(self.ctl, Ctl::Request(CtlRequest::Unpair))
+    }
+}
+
 /// Application control messages
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Ctl {
@@ -29,6 +48,83 @@ pub enum Ctl {
 pub enum CtlRequest {
     /// Request to launch a uri on the host device
     LaunchUri(String),
+    /// Request to show a short text snippet on the host device
+    SendText(String),
+    /// Request to send a file to the host device, the file's bytes follow once the host is ready
+    SendFile(FileInfo),
+    /// Request to continue an interrupted file transfer from what the host saved
+    ResumeFile(FileInfo),
+    /// Request to send a directory to the host device, the files' bytes follow once the host is ready
+    SendBundle(BundleInfo),
+    /// The clipboard of the client changed, every change is sent over the same session which is never responded to
+    Clipboard(String),
+    /// The client forgot the host, the host should forget the client too
+    Unpair,
+}
+
+/// Describes a file sent to a remote peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// The id of the transfer, which stays the same when the transfer is resumed
+    pub id: u64,
+    /// The name of the file, without any directories
+    pub name: String,
+    /// The size of the file in bytes
+    pub size: u64,
+}
+
+/// Describes a directory sent to a remote peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleInfo {
+    /// The id of the transfer
+    pub id: u64,
+    /// The name of the directory, without any parent directories
+    pub name: String,
+    /// The entries of the directory in the order they are sent, parents come before their children
+    pub entries: Vec<BundleEntry>,
+}
+
+impl BundleInfo {
+    /// The size of all the files in bytes
+    pub fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|e| match e.kind {
+                EntryKind::File { size } => size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The number of files
+    pub fn files(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File { .. }))
+            .count()
+    }
+}
+
+/// An entry of a directory sent to a remote peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry {
+    /// The path relative to the directory, separated by `/`
+    pub path: String,
+    /// What the entry is
+    pub kind: EntryKind,
+    /// The unix permission bits of the entry
+    pub mode: u32,
+}
+
+/// The kinds of entries in a directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A directory
+    Dir,
+    /// A file of the given size in bytes, its bytes are sent
+    File { size: u64 },
+    /// A symlink to the given path, relative to the symlink's directory and separated by `/`
+    Symlink { target: String },
 }
 
 /// The response from attempting to perform an app control request on a host
@@ -38,6 +134,10 @@ pub enum CtlResponse {
     Success,
     /// The host device is awaiting user input
     Waiting,
+    /// The host device is ready to receive the data of the app control request
+    Ready,
+    /// The host device is ready to receive the rest of an interrupted file from the given offset
+    Resume(u64),
     /// The host device failed to complete the app control request
     Error(u32),
     /// The host device declined to complete the app control request
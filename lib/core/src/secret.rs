@@ -1,6 +1,5 @@
-use std::collections::HashSet;
-
 use crate::err::CoreError;
+use crate::vault::{Vault, VaultKey};
 use base64::Engine;
 use p2p::peer;
 
@@ -10,23 +9,85 @@ pub static CERT: &str = "Certificate";
 pub static PK: &str = "PrivateKey";
 pub static TOTP: &str = "_Totp";
 
+/// Where the secrets of a node are kept
+pub trait SecretBackend: Send + Sync {
+    /// the secret stored under the key, None if there is none
+    fn get(&self, key: &str) -> Result<Option<String>, CoreError>;
+
+    /// store the secret under the key, replacing any secret stored under it before
+    fn set(&self, key: &str, val: &str) -> Result<(), CoreError>;
+
+    /// remove the secret stored under the key, removing a secret which isn't stored is not an error
+    fn remove(&self, key: &str) -> Result<(), CoreError>;
+}
+
+/// The backend secrets are kept in, chosen when the node is initialized
+#[derive(Debug, Clone)]
+pub enum Secrets {
+    /// the OS keyring, which needs a desktop session on linux
+    Keyring,
+    /// an encrypted vault file in the node's data directory, for hosts without a keyring
+    Vault(VaultKey),
+}
+
+impl Secrets {
+    pub(crate) fn open(self, dir: &std::path::Path) -> Result<Box<dyn SecretBackend>, CoreError> {
+        Ok(match self {
+            Secrets::Keyring => Box::new(Keyring),
+            Secrets::Vault(key) => Box::new(Vault::open(dir.join(crate::vault::VAULT_NAME), key)?),
+        })
+    }
+}
+
+/// The OS keyring, e.g. the Windows Credential Manager, the macOS Keychain or the Secret Service on linux
+pub struct Keyring;
+
+impl Keyring {
+    fn entry(key: &str) -> Result<keyring::Entry, CoreError> {
+        keyring::Entry::new(SVC, key).map_err(|err| CoreError::Secret(err.to_string()))
+    }
+}
+
+impl SecretBackend for Keyring {
+    fn get(&self, key: &str) -> Result<Option<String>, CoreError> {
+        match Self::entry(key)?.get_password() {
+            Ok(val) => Ok(Some(val)),
+            Err(keyring::error::Error::NoEntry) => Ok(None),
+            Err(err) => Err(CoreError::Secret(err.to_string())),
+        }
+    }
+
+    fn set(&self, key: &str, val: &str) -> Result<(), CoreError> {
+        Self::entry(key)?
+            .set_password(val)
+            .map_err(|err| CoreError::Secret(err.to_string()))
+    }
+
+    fn remove(&self, key: &str) -> Result<(), CoreError> {
+        match Self::entry(key)?.delete_password() {
+            Ok(()) | Err(keyring::error::Error::NoEntry) => Ok(()),
+            Err(err) => Err(CoreError::Secret(err.to_string())),
+        }
+    }
+}
+
 pub(crate) struct SecretStore {
     suf: String,
+    backend: Box<dyn SecretBackend>,
 }
 
 impl SecretStore {
-    pub fn new(suf: String) -> SecretStore {
-        SecretStore { suf }
+    pub fn new(suf: String, backend: Box<dyn SecretBackend>) -> SecretStore {
+        SecretStore { suf, backend }
     }
 
     pub fn get<'a, T>(&self, key: &str) -> Result<Option<T>, CoreError>
     where
         T: serde::de::DeserializeOwned,
     {
-        match self.get_entry(key)?.get_password() {
-            Ok(val) => Ok(serde_json::from_str(val.as_str())?),
-            Err(keyring::error::Error::NoEntry) => Ok(None),
-            Err(err) => Err(CoreError::Secret(err.to_string())),
+        match self.backend.get(&self.key(key))? {
+            Some(val) => Ok(serde_json::from_str(val.as_str())?),
+            None => Ok(None),
         }
     }
 
@@ -34,9 +95,12 @@ impl SecretStore {
     where
         T: serde::Serialize,
     {
-        self.get_entry(key)?
-            .set_password(serde_json::to_string(val)?.as_str())
-            .map_err(|err| CoreError::Secret(err.to_string()))
+        self.backend
+            .set(&self.key(key), serde_json::to_string(val)?.as_str())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), CoreError> {
+        self.backend.remove(&self.key(key))
     }
 
     pub fn put<'a, T>(&self, key: &str, op: impl FnOnce() -> T) -> Result<T, CoreError>
@@ -54,34 +118,26 @@ impl SecretStore {
         }
     }
 
-    fn get_entry(&self, key: &str) -> Result<keyring::Entry, CoreError> {
-        keyring::Entry::new(SVC, format!("{}_{}", key, self.suf).as_str())
-            .map_err(|err| CoreError::Secret(err.to_string()))
+    fn key(&self, key: &str) -> String {
+        format!("{}_{}", key, self.suf)
     }
 }
 
 impl SecretStore {
-    /// Get or create a new identity
-    pub(crate) fn get_identity(&self) -> Result<peer::Identity, CoreError> {
-        let (cert, key) = match (self.get::<String>(CERT), self.get::<String>(PK)) {
-            (Ok(Some(cert64)), Ok(Some(key64))) => {
-                let cert = base64::engine::general_purpose::STANDARD_NO_PAD
-                    .decode(cert64)
-                    .map_err(|e| CoreError::Base64(e.to_string()))?;
-                let key = base64::engine::general_purpose::STANDARD_NO_PAD
-                    .decode(key64)
-                    .map_err(|e| CoreError::Base64(e.to_string()))?;
-                (cert, key)
-            }
-            _ => {
-                let (cert, key) = peer::Identity::default().to_raw();
-                self.set(CERT, &cert)?;
-                self.set(PK, &key)?;
-                (cert, key)
-            }
+    /// the private key of the node's identity with the given id
+    pub(crate) fn get_private_key(&self, id: &peer::PeerId) -> Result<Option<Vec<u8>>, CoreError> {
+        let Some(key64) = self.get::<String>(&format!("{}_{}", id, PK))? else {
+            return Ok(None);
         };
+        let key = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(key64)
+            .map_err(|e| CoreError::Base64(e.to_string()))?;
+        Ok(Some(key))
+    }
 
-        Ok(peer::Identity::from_raw(cert, key))
+    pub(crate) fn set_private_key(&self, id: &peer::PeerId, key: &[u8]) -> Result<(), CoreError> {
+        let key64 = base64::engine::general_purpose::STANDARD_NO_PAD.encode(key);
+        self.set(&format!("{}_{}", id, PK), &key64)
     }
 
     pub(crate) fn get_totp(&self, peer: &peer::PeerId) -> Result<Option<String>, CoreError> {
@@ -94,16 +150,21 @@ impl SecretStore {
         self.set(&key, &totp)
     }
 
-    pub(crate) fn to_known(&self, peers: &HashSet<peer::PeerMetadata>) -> Vec<peer::PeerCandidate> {
-        let mut map = Vec::new();
-        for peer in peers {
-            if let Ok(Some(pwd)) = self.get_totp(&peer.id) {
-                if let Ok(auth) = p2p::pairing::PairingAuthenticator::new(pwd.into_bytes()) {
-                    map.push(peer::PeerCandidate::new(peer, auth));
-                }
-            }
-        }
-        map
+    pub(crate) fn remove_totp(&self, peer: &peer::PeerId) -> Result<(), CoreError> {
+        let key = peer.inner().clone() + TOTP;
+        self.remove(&key)
+    }
+
+    /// the candidate to connect to a known peer with, None if the peer's secret is missing
+    pub(crate) fn to_candidate(
+        &self,
+        peer: &peer::PeerMetadata,
+    ) -> Result<Option<peer::PeerCandidate>, CoreError> {
+        let Some(pwd) = self.get_totp(&peer.id)? else {
+            return Ok(None);
+        };
+        let auth = p2p::pairing::PairingAuthenticator::new(pwd.into_bytes())?;
+        Ok(Some(peer::PeerCandidate::new(peer, auth)))
     }
 }
 
@@ -1,16 +1,23 @@
 use std::collections::HashMap;
 
+use p2p::{pairing::PairingRequest, peer::PeerId};
 use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 
-use crate::proto::Session;
+use crate::{clipboard::Clipboard, proto::Session, transfer::PendingTransfer};
 
 #[derive(Debug, Default)]
 pub(crate) struct State {
     /// Cancellation token for discovery background task
     pub discovery_ct: Option<CancellationToken>,
-    /// Map of session senders
-    pub sessions: HashMap<u64, Sender<Session>>,
+    /// Map of session senders, keyed by the remote node as session ids are chosen by the node which started it
+    pub sessions: HashMap<(PeerId, u64), Sender<Session>>,
     /// An incrementing id for each unique session started with a remote node
     pub session_id: u64,
+    /// Pairing requests from nearby nodes waiting for the user to respond
+    pub pairing: HashMap<PeerId, PairingRequest>,
+    /// Files sent by remote nodes waiting for the user to accept them
+    pub transfers: HashMap<(PeerId, u64), PendingTransfer>,
+    /// Clipboard sessions with remote nodes
+    pub clipboard: Clipboard,
 }
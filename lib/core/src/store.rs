@@ -1,5 +1,7 @@
 use std::{fs, io, marker, path};
 
+use tracing::warn;
+
 pub struct Store<T>
 where
     T: Default,
@@ -38,38 +40,160 @@ impl<T: Default + Persistable> Store<T> {
         }
     }
 
+    /// Reads the item, writing the default if there is no file yet.
+    ///
+    /// A file which can't be read is never overwritten with the default, it is restored from the backup
+    /// instead & an `InvalidData` error is returned if there is no usable backup either.
     pub fn put(&self) -> Result<T, T::Error> {
-        let f = self.open_read()?;
-        let item = T::read(f).or_else(|e| -> Result<T, T::Error> {
-            let mut f = self.open_write()?;
-            let def = T::default();
-            def.write(&mut f)?;
-            Ok(def)
-        })?;
+        let f = match fs::File::open(self.path.as_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let def = T::default();
+                self.set(&def)?;
+                return Ok(def);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let e = match T::read(f) {
+            Ok(item) => return Ok(item),
+            Err(e) => e,
+        };
 
+        let backup = self.sibling("bak");
+        warn!(
+            "{} is damaged, restoring {}: {:?}",
+            self.path.display(),
+            backup.display(),
+            e
+        );
+        let item = fs::File::open(backup.as_path())
+            .ok()
+            .and_then(|f| T::read(f).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is damaged & has no usable backup", self.path.display()),
+                )
+            })?;
+        self.set(&item)?;
         Ok(item)
     }
 
+    /// Replaces the file with the item, a crash while writing leaves the old file in place.
+    ///
+    /// The old file is kept as a backup if it can be read, so the backup is always the last good item.
     pub fn set(&self, item: &T) -> Result<(), T::Error> {
-        let mut f = self.open_write()?;
-        item.write(&mut f)
+        let tmp = self.sibling("tmp");
+        let mut f = fs::File::create(tmp.as_path())?;
+        item.write(&mut f)?;
+        f.sync_all()?;
+        drop(f);
+
+        if self.get().is_ok() {
+            let backup = self.sibling("bak");
+            let staged = self.sibling("bak.tmp");
+            fs::copy(self.path.as_path(), staged.as_path())?;
+            fs::File::open(staged.as_path())?.sync_all()?;
+            fs::rename(staged.as_path(), backup.as_path())?;
+        }
+        fs::rename(tmp.as_path(), self.path.as_path())?;
+        self.sync_dir();
+        Ok(())
+    }
+
+    /// read the item without creating the file if it doesn't exist
+    pub fn get(&self) -> Result<T, T::Error> {
+        let f = fs::File::open(self.path.as_path())?;
+        T::read(f)
+    }
+
+    /// remove the file & its backup
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(self.sibling("bak")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::remove_file(self.path.as_path())
+    }
+
+    /// the path of a file kept next to the store's file, e.g. settings.json.bak
+    fn sibling(&self, suffix: &str) -> path::PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".");
+        name.push(suffix);
+        name.into()
+    }
+
+    /// make the renames durable, directories can't be synced on windows
+    fn sync_dir(&self) {
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            _ = fs::File::open(dir).and_then(|d| d.sync_all());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Persistable, Store};
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Count(u32);
+
+    impl Persistable for Count {
+        type Error = io::Error;
+
+        fn read<R: io::Read>(mut r: R) -> Result<Self, Self::Error> {
+            let mut s = String::new();
+            r.read_to_string(&mut s)?;
+            s.parse()
+                .map(Count)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        fn write<W: io::Write>(&self, w: &mut W) -> Result<(), Self::Error> {
+            write!(w, "{}", self.0)
+        }
     }
 
-    fn open_read(&self) -> io::Result<fs::File> {
-        fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .truncate(false)
-            .open(self.path.as_path())
+    fn store(name: &str) -> Store<Count> {
+        let dir = std::env::temp_dir().join("flydrop-store").join(name);
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("count").into()
     }
 
-    fn open_write(&self) -> io::Result<fs::File> {
-        fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(false)
-            .truncate(true)
-            .open(self.path.as_path())
+    #[test]
+    fn damaged_file_is_restored_from_backup() {
+        let store = store("restore");
+        assert_eq!(Count(0), store.put().unwrap());
+        store.set(&Count(1)).unwrap();
+        store.set(&Count(2)).unwrap();
+        assert_eq!("1", std::fs::read_to_string(store.sibling("bak")).unwrap());
+        assert!(!store.sibling("tmp").exists());
+
+        // e.g. a write which was cut short by a crash of an older version
+        std::fs::write(&store.path, "").unwrap();
+        assert_eq!(Count(1), store.put().unwrap());
+        assert_eq!(Count(1), store.get().unwrap());
+
+        store.remove().unwrap();
+        assert!(!store.sibling("bak").exists());
+    }
+
+    #[test]
+    fn damaged_file_without_backup_is_kept() {
+        let store = store("no-backup");
+        std::fs::write(&store.path, "not a count").unwrap();
+        let e = store.put().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert_eq!("not a count", std::fs::read_to_string(&store.path).unwrap());
+
+        // a damaged file never replaces the backup
+        std::fs::write(store.sibling("bak"), "3").unwrap();
+        store.set(&Count(4)).unwrap();
+        assert_eq!("3", std::fs::read_to_string(store.sibling("bak")).unwrap());
     }
 }
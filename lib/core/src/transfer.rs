@@ -0,0 +1,1421 @@
+use std::{
+    collections::HashSet,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use p2p::peer::{Peer, PeerId, PeerMetadata};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{
+        AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf,
+        WriteHalf,
+    },
+    sync::{
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    },
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, error};
+
+use crate::{
+    err,
+    node::InternalEvent,
+    proto::{
+        BundleEntry, BundleInfo, Ctl, CtlRequest, CtlResponse, EntryKind, FileInfo, Session,
+        SessionCodec, CTL_DIGEST_ERR, CTL_IO_ERR, CTL_NOT_FOUND, CTL_PATH_ERR,
+    },
+    store::{self, Store},
+};
+
+/// The size of the chunks a file is streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The length of a SHA-256 digest
+const DIGEST_LEN: usize = 32;
+
+/// How often progress is reported while a file is streamed
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+type Reader = FramedRead<ReadHalf<DuplexStream>, SessionCodec>;
+type Writer = FramedWrite<WriteHalf<DuplexStream>, SessionCodec>;
+
+/// A file sent by a remote peer which is waiting for the user to accept it
+#[derive(Debug)]
+pub(crate) struct PendingTransfer {
+    /// where the file will be saved
+    pub dest: PathBuf,
+    /// starts receiving the file once the destination is sent
+    pub accept: oneshot::Sender<PathBuf>,
+}
+
+/// The progress of a transfer which hasn't finished, persisted so an interrupted transfer can be resumed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferManifest {
+    /// the id both peers know the transfer by
+    pub id: u64,
+    /// the peer on the other end of the transfer
+    pub peer: PeerId,
+    /// the local file being sent or received
+    pub path: PathBuf,
+    /// the size of the file in bytes
+    pub size: u64,
+    /// the byte ranges of the file which are known to be transferred
+    pub completed: Vec<Range<u64>>,
+}
+
+impl TransferManifest {
+    /// record a range of the file as transferred
+    pub(crate) fn complete(&mut self, range: Range<u64>) {
+        self.completed.push(range);
+        self.completed.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.completed.len());
+        for r in self.completed.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        self.completed = merged;
+    }
+
+    /// the offset the transfer continues from, everything before it has been transferred
+    pub fn offset(&self) -> u64 {
+        match self.completed.first() {
+            Some(r) if r.start == 0 => r.end.min(self.size),
+            _ => 0,
+        }
+    }
+}
+
+impl store::Persistable for TransferManifest {
+    type Error = err::CoreError;
+
+    fn read<R>(r: R) -> Result<Self, Self::Error>
+    where
+        R: std::io::Read,
+    {
+        Ok(serde_json::from_reader(r)?)
+    }
+
+    fn write<W>(&self, w: &mut W) -> Result<(), Self::Error>
+    where
+        W: std::io::Write,
+    {
+        let json = serde_json::to_string(self)?;
+        w.write_all(json.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The directory the manifests of unfinished transfers are kept in
+#[derive(Debug, Clone)]
+pub(crate) struct Manifests(PathBuf);
+
+impl Manifests {
+    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+
+    /// the manifest of a file sent to a remote peer
+    pub(crate) fn sent(&self, id: u64) -> Store<TransferManifest> {
+        self.0.join(format!("send-{}.json", id)).into()
+    }
+
+    /// the manifest of a file received from a remote peer, the id is chosen by the peer so it's scoped to it
+    pub(crate) fn received(&self, peer: &PeerId, id: u64) -> Store<TransferManifest> {
+        self.0.join(format!("recv-{}-{}.json", peer, id)).into()
+    }
+
+    /// the files sent to remote peers which haven't finished, interrupted ones can be resumed
+    pub(crate) fn unfinished(&self) -> Vec<TransferManifest> {
+        let Ok(entries) = std::fs::read_dir(&self.0) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                // skip the temporary files & backups the stores keep next to the manifests
+                let name = e.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("send-") && name.ends_with(".json")
+            })
+            .filter_map(|e| Store::<TransferManifest>::new(e.path()).get().ok())
+            .collect()
+    }
+}
+
+/// describe the file at the given path so it can be sent to a remote peer
+pub(crate) async fn file_info(path: &Path) -> Result<FileInfo, err::CoreError> {
+    let meta = fs::metadata(path).await?;
+    let name = path
+        .file_name()
+        .filter(|_| meta.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "only files can be sent"))?;
+    Ok(FileInfo {
+        id: rand::random(),
+        name: name.to_string_lossy().into_owned(),
+        size: meta.len(),
+    })
+}
+
+/// send or resume sending a file to the remote peer, the file's bytes are streamed once the remote peer is ready
+pub(crate) async fn send(
+    peer: Peer,
+    req: Session,
+    path: PathBuf,
+    tx: UnboundedSender<InternalEvent>,
+    manifests: Manifests,
+) {
+    let sid = req.id;
+    let info = match &req.ctl {
+        Ctl::Request(CtlRequest::SendFile(info) | CtlRequest::ResumeFile(info)) => info.clone(),
+        _ => {
+            error!("Session {} is not a file transfer", sid);
+            return;
+        }
+    };
+    let store = manifests.sent(info.id);
+    let result = |ctl| InternalEvent::SessionResult {
+        id: peer.id.clone(),
+        body: Session { id: sid, ctl },
+    };
+    let interrupted = || InternalEvent::TransferInterrupted {
+        peer: peer.id.clone(),
+        id: info.id,
+    };
+
+    let mut file = match fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open {:?}: {}", path, e);
+            _ = tx.send(result(Ctl::Response(CtlResponse::Error(CTL_IO_ERR))));
+            return;
+        }
+    };
+
+    // a resumed transfer continues from what the remote peer saved
+    debug!("Offering file {:?} to peer {}", path, peer.id);
+    let (mut reader, mut stream, offset) = match offer(&peer, req, &tx).await {
+        Ok(ready) => ready,
+        Err(Some(ctl)) => {
+            // the transfer was declined or can't be resumed
+            _ = store.remove();
+            _ = tx.send(result(ctl));
+            return;
+        }
+        Err(None) => return,
+    };
+    if offset > info.size {
+        error!("Peer {} asked to resume from an invalid offset", peer.id);
+        _ = tx.send(result(Ctl::Response(CtlResponse::Error(CTL_IO_ERR))));
+        return;
+    }
+    let mut digest = match hash_prefix(&mut file, offset).await {
+        Ok(digest) => digest,
+        Err(e) => {
+            error!("Failed to read {:?}: {}", path, e);
+            _ = tx.send(result(Ctl::Response(CtlResponse::Error(CTL_IO_ERR))));
+            return;
+        }
+    };
+
+    // remember the transfer until the remote peer confirms it so it can be resumed
+    let mut manifest = TransferManifest {
+        id: info.id,
+        peer: peer.id.clone(),
+        path: path.clone(),
+        size: info.size,
+        completed: Vec::new(),
+    };
+    manifest.complete(0..offset);
+    if let Err(e) = store.set(&manifest) {
+        error!("Failed to save the transfer manifest: {}", e);
+    }
+
+    // stream the file in chunks which are each followed by their digest, then the digest of the whole file
+    let mut progress = Progress::new(peer.id.clone(), sid, info.size, offset, tx.clone());
+    let mut file = file.take(info.size - offset);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                error!("Failed to read {:?}: {}", path, e);
+                _ = tx.send(result(Ctl::Response(CtlResponse::Error(CTL_IO_ERR))));
+                return;
+            }
+        };
+        digest.update(&buf[..n]);
+        if let Err(e) = write_chunk(&mut stream, &buf[..n]).await {
+            error!("Failed to send file to peer {}: {}", peer.id, e);
+            _ = tx.send(interrupted());
+            return;
+        }
+        progress.advance(n);
+    }
+    let digest = digest.finish();
+    if let Err(e) = stream.write_all(digest.as_ref()).await {
+        error!("Failed to send file to peer {}: {}", peer.id, e);
+        _ = tx.send(interrupted());
+        return;
+    }
+    progress.finish();
+    _ = stream.shutdown().await;
+
+    // wait for the remote peer to confirm it saved the file, a corrupt chunk can be resumed from before it
+    let mut finished = false;
+    while let Some(Ok(session)) = reader.next().await {
+        let success = matches!(session.ctl, Ctl::Response(CtlResponse::Success));
+        if !matches!(
+            session.ctl,
+            Ctl::Response(CtlResponse::Error(CTL_DIGEST_ERR))
+        ) {
+            finished = true;
+            _ = store.remove();
+        }
+        if tx.send(result(session.ctl)).is_err() {
+            debug!("Failed to handle inbound response.");
+            break;
+        }
+        if success {
+            _ = tx.send(InternalEvent::TransferCompleted {
+                peer: peer.id.clone(),
+                sid,
+                path: path.clone(),
+                digest: hex(digest.as_ref()),
+            });
+        }
+    }
+    if !finished {
+        _ = tx.send(interrupted());
+    }
+    debug!("Ending file transfer with peer {}", peer.id);
+}
+
+/// open a stream & offer a transfer, forwarding the remote peer's responses until it is ready. Returns the offset to
+/// continue from, or the response the transfer ended with if the remote peer never became ready.
+async fn offer(
+    peer: &Peer,
+    req: Session,
+    tx: &UnboundedSender<InternalEvent>,
+) -> Result<(Reader, WriteHalf<DuplexStream>, u64), Option<Ctl>> {
+    let stream = match peer.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to open a stream to peer {}: {}", peer.id, e);
+            return Err(None);
+        }
+    };
+    let (r, w) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(r, SessionCodec::default());
+    let mut writer = FramedWrite::new(w, SessionCodec::default());
+    if writer.send(req).await.is_err() {
+        error!("Failed to send outbound request.");
+        return Err(None);
+    }
+
+    loop {
+        let Some(Ok(session)) = reader.next().await else {
+            error!("Peer {} ended the session before accepting", peer.id);
+            return Err(None);
+        };
+        let offset = match session.ctl {
+            Ctl::Response(CtlResponse::Ready) => Some(0),
+            Ctl::Response(CtlResponse::Resume(offset)) => Some(offset),
+            Ctl::Response(CtlResponse::Waiting) => None,
+            ctl => return Err(Some(ctl)),
+        };
+        let result = InternalEvent::SessionResult {
+            id: peer.id.clone(),
+            body: session,
+        };
+        if tx.send(result).is_err() {
+            debug!("Failed to handle inbound response.");
+            return Err(None);
+        }
+        if let Some(offset) = offset {
+            return Ok((reader, writer.into_inner(), offset));
+        }
+    }
+}
+
+/// receive or resume receiving a file from the remote peer, the transfer takes over the stream
+pub(crate) async fn receive(
+    reader: Reader,
+    mut writer: Writer,
+    meta: PeerMetadata,
+    req: Session,
+    tx: UnboundedSender<InternalEvent>,
+    manifests: Manifests,
+) {
+    let sid = req.id;
+    let response = |res| Session {
+        id: sid,
+        ctl: Ctl::Response(res),
+    };
+    let (opened, ready) = match req.ctl {
+        Ctl::Request(CtlRequest::SendFile(info)) => {
+            let Some(dest) = accept(&mut writer, &meta, sid, &info, None, &tx).await else {
+                return;
+            };
+            let opened = create(&dest).await.map(|(file, path)| {
+                let manifest = TransferManifest {
+                    id: info.id,
+                    peer: meta.id.clone(),
+                    path,
+                    size: info.size,
+                    completed: Vec::new(),
+                };
+                (file, manifest)
+            });
+            (opened, CtlResponse::Ready)
+        }
+        Ctl::Request(CtlRequest::ResumeFile(info)) => {
+            match reopen(&manifests, &meta.id, &info).await {
+                Ok((file, manifest)) => {
+                    let offset = manifest.offset();
+                    (Ok((file, manifest)), CtlResponse::Resume(offset))
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to resume transfer {} from peer {}: {}",
+                        info.id, meta.id, e
+                    );
+                    _ = writer
+                        .send(response(CtlResponse::Error(CTL_NOT_FOUND)))
+                        .await;
+                    return;
+                }
+            }
+        }
+        _ => {
+            error!("Session {} is not a file transfer", sid);
+            return;
+        }
+    };
+    let (mut file, mut manifest) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("Failed to create the file to receive: {}", e);
+            _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+            return;
+        }
+    };
+    let store = manifests.received(&manifest.peer, manifest.id);
+    if let Err(e) = store.set(&manifest) {
+        error!("Failed to save the transfer manifest: {}", e);
+    }
+    if writer.send(response(ready)).await.is_err() {
+        error!("Failed to send outbound response.");
+        return;
+    }
+
+    // save the file's chunks as they arrive & are verified, recording what was saved along the way
+    debug!("Receiving file {:?} from peer {}", manifest.path, meta.id);
+    let offset = manifest.offset();
+    let mut digest = match hash_prefix(&mut file, offset).await {
+        Ok(digest) => digest,
+        Err(e) => {
+            error!("Failed to read {:?}: {}", manifest.path, e);
+            _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+            return;
+        }
+    };
+    let mut progress = Progress::new(meta.id.clone(), sid, manifest.size, offset, tx.clone());
+    let mut stream = reader.into_inner();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = offset;
+    let mut saved = offset;
+    let mut interrupted = loop {
+        if received == manifest.size {
+            break false;
+        }
+        let n = match read_chunk(&mut stream, &mut buf, manifest.size - received).await {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // keep what was verified so far, the sender can resume from there
+                error!(
+                    "Peer {} sent a corrupt chunk of {:?} at {}: {}",
+                    meta.id, manifest.path, received, e
+                );
+                if file.sync_data().await.is_ok() {
+                    manifest.complete(saved..received);
+                    _ = store.set(&manifest);
+                }
+                _ = writer
+                    .send(response(CtlResponse::Error(CTL_DIGEST_ERR)))
+                    .await;
+                return;
+            }
+            Err(e) => {
+                debug!("Stream from peer {} failed: {}", meta.id, e);
+                break true;
+            }
+        };
+        digest.update(&buf[..n]);
+        if let Err(e) = file.write_all(&buf[..n]).await {
+            error!("Failed to write {:?}: {}", manifest.path, e);
+            drop(file);
+            _ = fs::remove_file(&manifest.path).await;
+            _ = store.remove();
+            _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+            return;
+        }
+        received += n as u64;
+        if progress.advance(n) && file.sync_data().await.is_ok() {
+            manifest.complete(saved..received);
+            saved = received;
+            _ = store.set(&manifest);
+        }
+    };
+
+    let mut expected = [0u8; DIGEST_LEN];
+    if !interrupted && stream.read_exact(&mut expected).await.is_err() {
+        interrupted = true;
+    }
+
+    // keep what was saved so the sender can resume once it is back
+    if interrupted {
+        if file.sync_data().await.is_ok() {
+            manifest.complete(saved..received);
+            _ = store.set(&manifest);
+        }
+        error!(
+            "Transfer of {:?} from peer {} was interrupted at {} of {} bytes",
+            manifest.path, meta.id, received, manifest.size
+        );
+        _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+        return;
+    }
+
+    // every chunk matched, the whole file has to match as well
+    let digest = digest.finish();
+    if digest.as_ref() != expected {
+        error!(
+            "The digest of {:?} from peer {} does not match",
+            manifest.path, meta.id
+        );
+        drop(file);
+        _ = fs::remove_file(&manifest.path).await;
+        _ = store.remove();
+        _ = writer
+            .send(response(CtlResponse::Error(CTL_DIGEST_ERR)))
+            .await;
+        return;
+    }
+
+    match file.sync_all().await {
+        Ok(()) => {
+            progress.finish();
+            _ = store.remove();
+            debug!("Received file {:?} from peer {}", manifest.path, meta.id);
+            _ = tx.send(InternalEvent::TransferCompleted {
+                peer: meta.id.clone(),
+                sid,
+                path: manifest.path.clone(),
+                digest: hex(digest.as_ref()),
+            });
+            _ = writer.send(response(CtlResponse::Success)).await;
+        }
+        Err(e) => {
+            error!("Failed to save {:?}: {}", manifest.path, e);
+            drop(file);
+            _ = fs::remove_file(&manifest.path).await;
+            _ = store.remove();
+            _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+        }
+    }
+}
+
+/// describe the directory at the given path so it can be sent to a remote peer
+pub(crate) async fn bundle_info(root: &Path) -> Result<BundleInfo, err::CoreError> {
+    let name = root
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the directory has no name"))?
+        .to_string_lossy()
+        .into_owned();
+    let dir = root.to_path_buf();
+    let entries = tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        walk(&dir, "", &mut entries).map(|_| entries)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    // don't offer what the remote peer would reject
+    validate(&entries).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(BundleInfo {
+        id: rand::random(),
+        name,
+        entries,
+    })
+}
+
+/// collect the entries of a directory, parents before their children
+fn walk(dir: &Path, prefix: &str, entries: &mut Vec<BundleEntry>) -> io::Result<()> {
+    let mut children = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|c| c.file_name());
+    for child in children {
+        let name = child.file_name().into_string().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "file names have to be utf8")
+        })?;
+        let path = format!("{}{}", prefix, name);
+        let meta = std::fs::symlink_metadata(child.path())?;
+        let kind = if meta.is_symlink() {
+            let target = std::fs::read_link(child.path())?;
+            let target = target.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "symlinks have to be utf8")
+            })?;
+            EntryKind::Symlink {
+                target: target.replace(std::path::MAIN_SEPARATOR, "/"),
+            }
+        } else if meta.is_dir() {
+            EntryKind::Dir
+        } else {
+            EntryKind::File { size: meta.len() }
+        };
+        let is_dir = matches!(kind, EntryKind::Dir);
+        entries.push(BundleEntry {
+            path: path.clone(),
+            kind,
+            mode: mode(&meta),
+        });
+        if is_dir {
+            walk(&child.path(), &format!("{}/", path), entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// check that every entry & every symlink's target stays inside of the directory. The targets are resolved without
+/// following other symlinks, so nothing may be resolved through a symlink of the bundle.
+pub(crate) fn validate(entries: &[BundleEntry]) -> Result<(), String> {
+    let links: HashSet<Vec<&str>> = entries
+        .iter()
+        .filter(|e| matches!(e.kind, EntryKind::Symlink { .. }))
+        .filter_map(|e| components(&e.path))
+        .collect();
+    let mut paths = HashSet::new();
+    for entry in entries {
+        let parts =
+            components(&entry.path).ok_or_else(|| format!("invalid path {}", entry.path))?;
+        if !paths.insert(parts.clone()) {
+            return Err(format!("duplicate path {}", entry.path));
+        }
+        if (1..parts.len()).any(|i| links.contains(&parts[..i])) {
+            return Err(format!("path {} is inside of a symlink", entry.path));
+        }
+
+        let EntryKind::Symlink { target } = &entry.kind else {
+            continue;
+        };
+        if target.is_empty() || target.starts_with('/') || target.contains(['\\', ':']) {
+            return Err(format!("symlink {} has an invalid target", entry.path));
+        }
+        let mut resolved = parts[..parts.len() - 1].to_vec();
+        let target: Vec<&str> = target.split('/').collect();
+        for (i, part) in target.iter().enumerate() {
+            match *part {
+                "" | "." => {}
+                ".." => {
+                    if resolved.pop().is_none() {
+                        return Err(format!("symlink {} points outside of the root", entry.path));
+                    }
+                }
+                part => {
+                    resolved.push(part);
+                    if i < target.len() - 1 && links.contains(&resolved) {
+                        return Err(format!("symlink {} points through a symlink", entry.path));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// split a relative path into its parts, none of which may leave or stay at the same directory
+fn components(path: &str) -> Option<Vec<&str>> {
+    let parts: Vec<&str> = path.split('/').collect();
+    let valid = parts
+        .iter()
+        .all(|p| !p.is_empty() && *p != "." && *p != ".." && !p.contains(['\\', ':']));
+    valid.then_some(parts)
+}
+
+/// send a directory to the remote peer, the files' bytes are streamed one after another once the remote peer is ready
+pub(crate) async fn send_bundle(
+    peer: Peer,
+    req: Session,
+    root: PathBuf,
+    tx: UnboundedSender<InternalEvent>,
+) {
+    let sid = req.id;
+    let Ctl::Request(CtlRequest::SendBundle(bundle)) = &req.ctl else {
+        error!("Session {} is not a directory transfer", sid);
+        return;
+    };
+    let bundle = bundle.clone();
+    let result = |ctl| InternalEvent::SessionResult {
+        id: peer.id.clone(),
+        body: Session { id: sid, ctl },
+    };
+
+    debug!("Offering directory {:?} to peer {}", root, peer.id);
+    let (mut reader, mut stream, _) = match offer(&peer, req, &tx).await {
+        Ok(ready) => ready,
+        Err(Some(ctl)) => {
+            _ = tx.send(result(ctl));
+            return;
+        }
+        Err(None) => return,
+    };
+
+    // each file is sent the same way as a single file
+    let mut progress = Progress::new(peer.id.clone(), sid, bundle.size(), 0, tx.clone());
+    let mut digests = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; CHUNK_SIZE];
+    for entry in &bundle.entries {
+        let EntryKind::File { size } = entry.kind else {
+            continue;
+        };
+        let path: PathBuf = root.join(entry.path.split('/').collect::<PathBuf>());
+        match send_entry(&mut stream, &path, size, &mut buf, &mut progress).await {
+            Ok(digest) => digests.update(digest.as_ref()),
+            Err(e) => {
+                // the remote peer removes what it saved once the stream ends early
+                error!("Failed to send {:?} to peer {}: {}", path, peer.id, e);
+                _ = tx.send(result(Ctl::Response(CtlResponse::Error(CTL_IO_ERR))));
+                return;
+            }
+        }
+    }
+    progress.finish();
+    _ = stream.shutdown().await;
+
+    let digest = digests.finish();
+    while let Some(Ok(session)) = reader.next().await {
+        let success = matches!(session.ctl, Ctl::Response(CtlResponse::Success));
+        if tx.send(result(session.ctl)).is_err() {
+            debug!("Failed to handle inbound response.");
+            break;
+        }
+        if success {
+            _ = tx.send(InternalEvent::TransferCompleted {
+                peer: peer.id.clone(),
+                sid,
+                path: root.clone(),
+                digest: hex(digest.as_ref()),
+            });
+        }
+    }
+    debug!("Ending directory transfer with peer {}", peer.id);
+}
+
+/// send a file of a directory in chunks followed by its digest
+async fn send_entry(
+    stream: &mut WriteHalf<DuplexStream>,
+    path: &Path,
+    size: u64,
+    buf: &mut [u8],
+    progress: &mut Progress,
+) -> io::Result<digest::Digest> {
+    let mut file = fs::File::open(path).await?.take(size);
+    let mut digest = digest::Context::new(&digest::SHA256);
+    let mut sent = 0;
+    loop {
+        let n = file.read(buf).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+        write_chunk(stream, &buf[..n]).await?;
+        sent += n as u64;
+        progress.advance(n);
+    }
+    if sent < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the file changed while it was sent",
+        ));
+    }
+    let digest = digest.finish();
+    stream.write_all(digest.as_ref()).await?;
+    Ok(digest)
+}
+
+/// receive a directory from the remote peer, recreating it under the download directory
+pub(crate) async fn receive_bundle(
+    reader: Reader,
+    mut writer: Writer,
+    meta: PeerMetadata,
+    req: Session,
+    tx: UnboundedSender<InternalEvent>,
+) {
+    let sid = req.id;
+    let response = |res| Session {
+        id: sid,
+        ctl: Ctl::Response(res),
+    };
+    let Ctl::Request(CtlRequest::SendBundle(bundle)) = req.ctl else {
+        error!("Session {} is not a directory transfer", sid);
+        return;
+    };
+    if let Err(e) = validate(&bundle.entries) {
+        error!("Peer {} sent an invalid directory: {}", meta.id, e);
+        _ = writer
+            .send(response(CtlResponse::Error(CTL_PATH_ERR)))
+            .await;
+        return;
+    }
+
+    let info = FileInfo {
+        id: bundle.id,
+        name: bundle.name.clone(),
+        size: bundle.size(),
+    };
+    let files = Some(bundle.files());
+    let Some(dest) = accept(&mut writer, &meta, sid, &info, files, &tx).await else {
+        return;
+    };
+    let root = match create_dir(&dest).await {
+        Ok(root) => root,
+        Err(e) => {
+            error!("Failed to create {:?}: {}", dest, e);
+            _ = writer.send(response(CtlResponse::Error(CTL_IO_ERR))).await;
+            return;
+        }
+    };
+    if writer.send(response(CtlResponse::Ready)).await.is_err() {
+        error!("Failed to send outbound response.");
+        _ = fs::remove_dir_all(&root).await;
+        return;
+    }
+
+    debug!("Receiving directory {:?} from peer {}", root, meta.id);
+    let mut progress = Progress::new(meta.id.clone(), sid, info.size, 0, tx.clone());
+    let mut stream = reader.into_inner();
+    match receive_entries(&mut stream, &root, &bundle, &mut progress).await {
+        Ok(digest) => {
+            progress.finish();
+            debug!("Received directory {:?} from peer {}", root, meta.id);
+            _ = tx.send(InternalEvent::TransferCompleted {
+                peer: meta.id.clone(),
+                sid,
+                path: root,
+                digest: hex(digest.as_ref()),
+            });
+            _ = writer.send(response(CtlResponse::Success)).await;
+        }
+        Err(e) => {
+            // a directory isn't resumed, nothing of it is kept
+            error!(
+                "Failed to receive directory {:?} from peer {}: {}",
+                root, meta.id, e
+            );
+            _ = fs::remove_dir_all(&root).await;
+            let code = match e.kind() {
+                io::ErrorKind::InvalidData => CTL_DIGEST_ERR,
+                io::ErrorKind::InvalidInput => CTL_PATH_ERR,
+                _ => CTL_IO_ERR,
+            };
+            _ = writer.send(response(CtlResponse::Error(code))).await;
+        }
+    }
+}
+
+/// save the entries of a directory as they arrive, symlinks are created last so nothing is written through them
+async fn receive_entries(
+    stream: &mut ReadHalf<DuplexStream>,
+    root: &Path,
+    bundle: &BundleInfo,
+    progress: &mut Progress,
+) -> io::Result<digest::Digest> {
+    let mut digests = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; CHUNK_SIZE];
+    for entry in &bundle.entries {
+        let path = root.join(entry.path.split('/').collect::<PathBuf>());
+        match entry.kind {
+            EntryKind::Dir => fs::create_dir_all(&path).await?,
+            EntryKind::File { size } => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .await?;
+                let mut digest = digest::Context::new(&digest::SHA256);
+                let mut remaining = size;
+                while remaining > 0 {
+                    let n = read_chunk(stream, &mut buf, remaining).await?;
+                    digest.update(&buf[..n]);
+                    file.write_all(&buf[..n]).await?;
+                    remaining -= n as u64;
+                    progress.advance(n);
+                }
+                let mut expected = [0u8; DIGEST_LEN];
+                stream.read_exact(&mut expected).await?;
+                let digest = digest.finish();
+                if digest.as_ref() != expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "file digest does not match",
+                    ));
+                }
+                file.sync_all().await?;
+                set_mode(&path, entry.mode).await?;
+                digests.update(digest.as_ref());
+            }
+            EntryKind::Symlink { .. } => {}
+        }
+    }
+    for entry in &bundle.entries {
+        if let EntryKind::Symlink { target } = &entry.kind {
+            let path = root.join(entry.path.split('/').collect::<PathBuf>());
+            symlink(target, &path).await?;
+            if !resolves_inside(root, &path, target).await? {
+                _ = fs::remove_file(&path).await;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("symlink {} points outside of the directory", entry.path),
+                ));
+            }
+        }
+    }
+
+    // directories last, a read-only directory can't be written to
+    for entry in bundle.entries.iter().rev() {
+        if let EntryKind::Dir = entry.kind {
+            let path = root.join(entry.path.split('/').collect::<PathBuf>());
+            set_mode(&path, entry.mode).await?;
+        }
+    }
+    Ok(digests.finish())
+}
+
+/// create the directory to save to, a number is added to the name when it already exists
+async fn create_dir(dest: &Path) -> io::Result<PathBuf> {
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let mut path = dest.to_path_buf();
+    for n in 1.. {
+        match fs::create_dir(&path).await {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path.set_file_name(format!("{} ({})", name, n));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(unix)]
+fn mode(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode(meta: &std::fs::Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// apply the permission bits sent by the remote peer, special bits such as setuid are never applied
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).await
+}
+
+#[cfg(not(unix))]
+async fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut perms = fs::metadata(path).await?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, perms).await
+}
+
+#[cfg(unix)]
+async fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    fs::symlink(target, path).await
+}
+
+/// Resolve the symlink's target on the filesystem & check that it stays inside of the root. The filesystem may fold
+/// case or unicode normalization where [validate] compared the paths exactly, so the parts which exist are resolved
+/// by the filesystem itself. Nothing can be resolved through a part which doesn't exist, so a target can't go back
+/// up after one.
+async fn resolves_inside(root: &Path, link: &Path, target: &str) -> io::Result<bool> {
+    let root = fs::canonicalize(root).await?;
+    let Some(parent) = link.parent() else {
+        return Ok(false);
+    };
+    let mut resolved = fs::canonicalize(parent).await?;
+    let mut missing = false;
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if missing => return Ok(false),
+            ".." => {
+                resolved.pop();
+            }
+            part => {
+                resolved.push(part);
+                if !missing {
+                    match fs::canonicalize(&resolved).await {
+                        Ok(path) => resolved = path,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => missing = true,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        if !resolved.starts_with(&root) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// symlinks need special privileges on other platforms so they are skipped
+#[cfg(not(unix))]
+async fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    debug!("Skipping symlink {:?} to {}", path, target);
+    Ok(())
+}
+
+/// let the node decide whether to accept the file, forwarding its responses until it does
+async fn accept(
+    writer: &mut Writer,
+    meta: &PeerMetadata,
+    sid: u64,
+    info: &FileInfo,
+    bundle: Option<usize>,
+    tx: &UnboundedSender<InternalEvent>,
+) -> Option<PathBuf> {
+    let (res_tx, mut res_rx) = mpsc::channel(64);
+    let (accept_tx, mut accept_rx) = oneshot::channel();
+    if tx
+        .send(InternalEvent::InboundTransfer {
+            meta: meta.clone(),
+            sid,
+            file: info.clone(),
+            bundle,
+            tx: res_tx,
+            accept: accept_tx,
+        })
+        .is_err()
+    {
+        debug!("Failed to handle inbound request.");
+        return None;
+    }
+    loop {
+        tokio::select! {
+            dest = &mut accept_rx => match dest {
+                Ok(dest) => return Some(dest),
+                Err(_) => {
+                    // declined, send whatever the node responded with
+                    while let Some(res) = res_rx.recv().await {
+                        _ = writer.send(res).await;
+                    }
+                    return None;
+                }
+            },
+            Some(res) = res_rx.recv() => {
+                if writer.send(res).await.is_err() {
+                    error!("Failed to send outbound response.");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// reopen the partially received file of an interrupted transfer, dropping anything saved after the manifest
+async fn reopen(
+    manifests: &Manifests,
+    peer: &PeerId,
+    info: &FileInfo,
+) -> Result<(fs::File, TransferManifest), err::CoreError> {
+    let manifest = manifests.received(peer, info.id).get()?;
+    if manifest.peer != *peer || manifest.size != info.size {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no matching transfer").into());
+    }
+    let offset = manifest.offset();
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&manifest.path)
+        .await?;
+    if file.metadata().await?.len() < offset {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file is incomplete").into());
+    }
+    file.set_len(offset).await?;
+    Ok((file, manifest))
+}
+
+/// hash the start of a file which was already transferred, leaving the file positioned after it
+async fn hash_prefix(file: &mut fs::File, len: u64) -> io::Result<digest::Context> {
+    let mut digest = digest::Context::new(&digest::SHA256);
+    file.seek(SeekFrom::Start(0)).await?;
+    if len == 0 {
+        return Ok(digest);
+    }
+    let mut prefix = file.take(len);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut read = 0;
+    loop {
+        match prefix.read(&mut buf).await? {
+            0 => break,
+            n => {
+                digest.update(&buf[..n]);
+                read += n as u64;
+            }
+        }
+    }
+    if read < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(digest)
+}
+
+/// write a chunk of the file followed by its digest
+async fn write_chunk<W>(w: &mut W, chunk: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    w.write_u32(chunk.len() as u32).await?;
+    w.write_all(chunk).await?;
+    w.write_all(digest::digest(&digest::SHA256, chunk).as_ref())
+        .await
+}
+
+/// read a chunk of the file into the buffer, a chunk which is too large or doesn't match its digest is invalid data
+async fn read_chunk<R>(r: &mut R, buf: &mut [u8], remaining: u64) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = r.read_u32().await? as usize;
+    if len == 0 || len > buf.len() || len as u64 > remaining {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid chunk length",
+        ));
+    }
+    r.read_exact(&mut buf[..len]).await?;
+    let mut expected = [0u8; DIGEST_LEN];
+    r.read_exact(&mut expected).await?;
+    if digest::digest(&digest::SHA256, &buf[..len]).as_ref() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk digest does not match",
+        ));
+    }
+    Ok(len)
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// create the file to save to, a number is added to the name when a file with the same name already exists
+async fn create(dest: &Path) -> io::Result<(fs::File, PathBuf)> {
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = dest
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()));
+    let mut path = dest.to_path_buf();
+    for n in 1.. {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let name = format!("{} ({}){}", stem, n, ext.as_deref().unwrap_or_default());
+                path.set_file_name(name);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Reports the progress of a transfer to the node
+struct Progress {
+    peer: PeerId,
+    sid: u64,
+    total: u64,
+    bytes: u64,
+    /// the bytes transferred before this session, which don't count towards the rate
+    resumed: u64,
+    started: Instant,
+    reported: Instant,
+    tx: UnboundedSender<InternalEvent>,
+}
+
+impl Progress {
+    fn new(
+        peer: PeerId,
+        sid: u64,
+        total: u64,
+        resumed: u64,
+        tx: UnboundedSender<InternalEvent>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            peer,
+            sid,
+            total,
+            bytes: resumed,
+            resumed,
+            started: now,
+            reported: now,
+            tx,
+        }
+    }
+
+    /// count the bytes transferred, reporting at most once per interval. Returns whether progress was reported.
+    fn advance(&mut self, n: usize) -> bool {
+        self.bytes += n as u64;
+        let report = self.reported.elapsed() >= PROGRESS_INTERVAL;
+        if report {
+            self.report();
+        }
+        report
+    }
+
+    /// report the final progress of the transfer
+    fn finish(&mut self) {
+        self.report();
+    }
+
+    fn report(&mut self) {
+        self.reported = Instant::now();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => ((self.bytes - self.resumed) as f64 / elapsed) as u64,
+            false => 0,
+        };
+        _ = self.tx.send(InternalEvent::TransferProgress {
+            peer: self.peer.clone(),
+            sid: self.sid,
+            bytes: self.bytes,
+            total: self.total,
+            rate,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use p2p::peer::PeerId;
+
+    use super::{
+        read_chunk, receive_entries, validate, write_chunk, Manifests, Progress, TransferManifest,
+        CHUNK_SIZE,
+    };
+    use crate::proto::{BundleEntry, BundleInfo, EntryKind};
+
+    fn dir(path: &str) -> BundleEntry {
+        BundleEntry {
+            path: path.to_string(),
+            kind: EntryKind::Dir,
+            mode: 0o755,
+        }
+    }
+
+    fn file(path: &str) -> BundleEntry {
+        BundleEntry {
+            path: path.to_string(),
+            kind: EntryKind::File { size: 1 },
+            mode: 0o644,
+        }
+    }
+
+    fn link(path: &str, target: &str) -> BundleEntry {
+        BundleEntry {
+            path: path.to_string(),
+            kind: EntryKind::Symlink {
+                target: target.to_string(),
+            },
+            mode: 0o777,
+        }
+    }
+
+    #[test]
+    fn bundle_paths_stay_inside_the_root() {
+        assert!(validate(&[
+            dir("lib"),
+            file("lib/libfd.so.1"),
+            link("lib/libfd.so", "libfd.so.1"),
+            dir("bin"),
+            link("bin/libfd.so", "../lib/libfd.so"),
+            link("root", "."),
+        ])
+        .is_ok());
+
+        for path in ["../x", "/x", "a/../x", "a/./x", "a//x", "a\\x", "C:x", ""] {
+            assert!(validate(&[file(path)]).is_err(), "{}", path);
+        }
+        assert!(validate(&[file("x"), file("x")]).is_err());
+    }
+
+    #[test]
+    fn bundle_symlinks_stay_inside_the_root() {
+        assert!(validate(&[dir("a"), link("a/up", "../..")]).is_err());
+        assert!(validate(&[link("abs", "/etc/passwd")]).is_err());
+        assert!(validate(&[link("drive", "C:/Windows")]).is_err());
+
+        // resolving through a symlink could end up anywhere
+        assert!(validate(&[link("d", "."), link("e", "d/../x")]).is_err());
+        assert!(validate(&[link("d", "."), file("d/x")]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn received_symlinks_stay_inside_the_root() {
+        let tmp = std::env::temp_dir().join("flydrop-bundle");
+        _ = std::fs::remove_dir_all(&tmp);
+        let bundle = |entries| BundleInfo {
+            id: 1,
+            name: String::from("bundle"),
+            entries,
+        };
+        // the steps receive_bundle takes, nothing is created unless the entries are valid
+        let receive = |bundle: BundleInfo, root: std::path::PathBuf| async move {
+            validate(&bundle.entries)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            std::fs::create_dir_all(&root).unwrap();
+            let (stream, _remote) = tokio::io::duplex(64);
+            let (mut stream, _) = tokio::io::split(stream);
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut progress = Progress::new(PeerId::default(), 1, 0, 0, tx);
+            receive_entries(&mut stream, &root, &bundle, &mut progress).await
+        };
+
+        // links which stay inside, including one to another link & one to a file which doesn't exist
+        let entries = vec![
+            dir("a"),
+            dir("a/b"),
+            link("a/b/L", "../.."),
+            link("a/M", "b/L"),
+            link("y", "a/missing"),
+        ];
+        let root = tmp.join("inside");
+        assert!(receive(bundle(entries), root.clone()).await.is_ok());
+        assert!(root.join("a/M").symlink_metadata().is_ok());
+        assert!(root.join("y").symlink_metadata().is_ok());
+
+        // the case variant of a link passes validation but only escapes once the earlier link is resolved, on a case
+        // insensitive filesystem. Going up from a part which doesn't exist is rejected everywhere
+        let entries = vec![
+            dir("a"),
+            dir("a/b"),
+            link("a/b/L", "../.."),
+            link("x", "a/b/l/../../.."),
+        ];
+        let root = tmp.join("case");
+        let e = receive(bundle(entries), root.clone()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+        assert!(root.join("a/b/L").symlink_metadata().is_ok());
+        assert!(root.join("x").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn manifest_merges_completed_ranges() {
+        let mut manifest = TransferManifest {
+            size: 100,
+            ..Default::default()
+        };
+        assert_eq!(0, manifest.offset());
+
+        manifest.complete(40..60);
+        assert_eq!(0, manifest.offset());
+        manifest.complete(0..20);
+        manifest.complete(20..40);
+        assert_eq!(vec![0..60], manifest.completed);
+        assert_eq!(60, manifest.offset());
+
+        manifest.complete(50..120);
+        assert_eq!(100, manifest.offset());
+    }
+
+    #[test]
+    fn received_manifests_are_scoped_to_the_peer() {
+        let tmp = std::env::temp_dir()
+            .join("flydrop-transfer")
+            .join("manifests");
+        _ = std::fs::remove_dir_all(&tmp);
+        let manifests = Manifests::new(tmp.clone()).unwrap();
+
+        // both peers chose the same transfer id
+        let a = PeerId::from_string("a".repeat(40)).unwrap();
+        let b = PeerId::from_string("b".repeat(40)).unwrap();
+        for peer in [&a, &b] {
+            let manifest = TransferManifest {
+                id: 7,
+                peer: peer.clone(),
+                path: tmp.join(peer.to_string()),
+                ..Default::default()
+            };
+            manifests.received(peer, 7).set(&manifest).unwrap();
+        }
+        assert_eq!(
+            tmp.join(a.to_string()),
+            manifests.received(&a, 7).get().unwrap().path
+        );
+        assert_eq!(
+            tmp.join(b.to_string()),
+            manifests.received(&b, 7).get().unwrap().path
+        );
+    }
+
+    #[tokio::test]
+    async fn chunks_are_verified() {
+        let mut wire = Vec::new();
+        write_chunk(&mut wire, b"hello").await.unwrap();
+        write_chunk(&mut wire, b"world").await.unwrap();
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut r = &wire[..];
+        assert_eq!(5, read_chunk(&mut r, &mut buf, 10).await.unwrap());
+        assert_eq!(b"hello", &buf[..5]);
+        assert_eq!(5, read_chunk(&mut r, &mut buf, 5).await.unwrap());
+        assert_eq!(b"world", &buf[..5]);
+
+        // a chunk larger than what is left of the file is rejected
+        let mut r = &wire[..];
+        let e = read_chunk(&mut r, &mut buf, 4).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        // a tampered chunk doesn't match its digest
+        wire[4] = b'j';
+        let mut r = &wire[..];
+        let e = read_chunk(&mut r, &mut buf, 10).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
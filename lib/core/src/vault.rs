@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    aead, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{err::CoreError, secret::SecretBackend};
+
+/// The name of the vault file in the node's data directory
+pub static VAULT_NAME: &str = "secrets.vault";
+
+/// How many PBKDF2 iterations a new vault's key is derived with
+const ITERATIONS: u32 = 100_000;
+
+/// The length of the salt the vault's key is derived with
+const SALT_LEN: usize = 16;
+
+/// What unlocks a vault
+#[derive(Clone)]
+pub enum VaultKey {
+    /// a passphrase the user enters when the node starts
+    Passphrase(String),
+    /// a file whose contents are the passphrase, e.g. one mounted from a container secret
+    KeyFile(PathBuf),
+}
+
+impl std::fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // never log the passphrase
+            VaultKey::Passphrase(_) => f.write_str("Passphrase(..)"),
+            VaultKey::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+        }
+    }
+}
+
+/// The vault as it's written to disk, the secrets are encrypted with AES-256-GCM
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultFile {
+    /// the salt of the key derivation, base64 encoded
+    salt: String,
+    /// the number of PBKDF2-HMAC-SHA256 iterations
+    iterations: u32,
+    /// the nonce the secrets were sealed with, base64 encoded
+    nonce: String,
+    /// the sealed secrets & their tag, base64 encoded
+    data: String,
+}
+
+/// Secrets kept in an encrypted file, for hosts without an OS keyring
+pub struct Vault {
+    path: PathBuf,
+    salt: Vec<u8>,
+    iterations: u32,
+    key: aead::LessSafeKey,
+    secrets: Mutex<HashMap<String, String>>,
+}
+
+impl Vault {
+    /// unlock the vault at the path, a new vault is created if there is no file
+    pub fn open(path: PathBuf, key: VaultKey) -> Result<Self, CoreError> {
+        let material = match key {
+            VaultKey::Passphrase(passphrase) => passphrase.into_bytes(),
+            VaultKey::KeyFile(file) => fs::read(file)?,
+        };
+        if material.is_empty() {
+            return Err(CoreError::Secret(String::from("the vault key is empty")));
+        }
+
+        if !path.exists() {
+            let mut salt = vec![0; SALT_LEN];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .map_err(|_| unspecified())?;
+            let vault = Self {
+                key: derive(&material, &salt, ITERATIONS)?,
+                path,
+                salt,
+                iterations: ITERATIONS,
+                secrets: Mutex::new(HashMap::new()),
+            };
+            vault.write(&HashMap::new())?;
+            return Ok(vault);
+        }
+
+        let file: VaultFile = serde_json::from_slice(&fs::read(&path)?)?;
+        let salt = decode(&file.salt)?;
+        let nonce = decode(&file.nonce)?;
+        let mut data = decode(&file.data)?;
+        let key = derive(&material, &salt, file.iterations)?;
+        let nonce = aead::Nonce::try_assume_unique_for_key(&nonce).map_err(|_| unspecified())?;
+        let plain = key
+            .open_in_place(nonce, aead::Aad::empty(), &mut data)
+            .map_err(|_| {
+                CoreError::Secret(String::from(
+                    "the vault could not be unlocked, the passphrase or key file is wrong",
+                ))
+            })?;
+        let secrets = serde_json::from_slice(plain)?;
+        Ok(Self {
+            path,
+            salt,
+            iterations: file.iterations,
+            key,
+            secrets: Mutex::new(secrets),
+        })
+    }
+
+    /// seal the secrets with a new nonce & replace the file with them
+    fn write(&self, secrets: &HashMap<String, String>) -> Result<(), CoreError> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| unspecified())?;
+        let mut data = serde_json::to_vec(secrets)?;
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut data,
+            )
+            .map_err(|_| unspecified())?;
+        let file = VaultFile {
+            salt: STANDARD.encode(&self.salt),
+            iterations: self.iterations,
+            nonce: STANDARD.encode(nonce),
+            data: STANDARD.encode(data),
+        };
+
+        // a crash while writing leaves the old vault in place
+        let tmp = self.path.with_extension("tmp");
+        let mut f = create(&tmp)?;
+        f.write_all(&serde_json::to_vec(&file)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// change the secrets & write them, the secrets are left as they were if writing fails
+    fn update(&self, op: impl FnOnce(&mut HashMap<String, String>)) -> Result<(), CoreError> {
+        let mut secrets = self.secrets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut updated = secrets.clone();
+        op(&mut updated);
+        self.write(&updated)?;
+        *secrets = updated;
+        Ok(())
+    }
+}
+
+impl SecretBackend for Vault {
+    fn get(&self, key: &str) -> Result<Option<String>, CoreError> {
+        let secrets = self.secrets.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(secrets.get(key).cloned())
+    }
+
+    fn set(&self, key: &str, val: &str) -> Result<(), CoreError> {
+        self.update(|secrets| {
+            secrets.insert(key.to_string(), val.to_string());
+        })
+    }
+
+    fn remove(&self, key: &str) -> Result<(), CoreError> {
+        self.update(|secrets| {
+            secrets.remove(key);
+        })
+    }
+}
+
+/// derive the vault's key from the passphrase or key file
+fn derive(material: &[u8], salt: &[u8], iterations: u32) -> Result<aead::LessSafeKey, CoreError> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(unspecified)?;
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        material,
+        &mut key,
+    );
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key).map_err(|_| unspecified())?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+fn decode(val: &str) -> Result<Vec<u8>, CoreError> {
+    STANDARD
+        .decode(val)
+        .map_err(|e| CoreError::Base64(e.to_string()))
+}
+
+fn unspecified() -> CoreError {
+    CoreError::Secret(String::from("the vault is damaged"))
+}
+
+/// create a file only the current user can read
+fn create(path: &Path) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::SecretBackend;
+
+    use super::{Vault, VaultKey};
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join("flydrop-vault").join(name);
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn secrets_survive_reopening() {
+        let path = dir("passphrase").join("secrets.vault");
+        let key = VaultKey::Passphrase(String::from("correct horse"));
+        let vault = Vault::open(path.clone(), key.clone()).unwrap();
+        vault.set("a", "1").unwrap();
+        vault.set("b", "2").unwrap();
+        vault.remove("b").unwrap();
+        vault.remove("missing").unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("\"1\""));
+
+        let vault = Vault::open(path.clone(), key).unwrap();
+        assert_eq!(Some(String::from("1")), vault.get("a").unwrap());
+        assert_eq!(None, vault.get("b").unwrap());
+
+        let wrong = VaultKey::Passphrase(String::from("battery staple"));
+        assert!(Vault::open(path, wrong).is_err());
+    }
+
+    #[test]
+    fn key_file_unlocks_the_vault() {
+        let dir = dir("keyfile");
+        let file = dir.join("vault.key");
+        std::fs::write(&file, b"0123456789abcdef").unwrap();
+        let path = dir.join("secrets.vault");
+        let vault = Vault::open(path.clone(), VaultKey::KeyFile(file.clone())).unwrap();
+        vault.set("a", "1").unwrap();
+
+        let vault = Vault::open(path.clone(), VaultKey::KeyFile(file)).unwrap();
+        assert_eq!(Some(String::from("1")), vault.get("a").unwrap());
+        assert!(Vault::open(path, VaultKey::KeyFile(dir.join("missing.key"))).is_err());
+    }
+}
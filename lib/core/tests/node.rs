@@ -1,10 +1,25 @@
-use std::time::Duration;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
 
 use fdcore::{
     api::{cmd::PeerRequest, event::{CoreEvent, ControlStatus, ControlMessage}},
-    node::{Node},
+    conf::{RequestKind, Rule},
+    node::{Node, QrPayload},
+    p2p::{
+        discovery,
+        event::DiscoveryEvent,
+        peer::{PeerId, PeerMetadata},
+        proto::DiscoveryCodec,
+    },
+    secret::Secrets,
+    vault::VaultKey,
+    transfer::TransferManifest,
 };
+use futures::SinkExt;
 use tokio::task::Id;
+use tokio_util::udp::UdpFramed;
 
 // #[tokio::test]
 // pub async fn node_discovery_test() -> Result<(), Box<dyn std::error::Error>> {
@@ -54,8 +69,8 @@ pub async fn nodes_pair_send_openuri() -> Result<(), Box<dyn std::error::Error>>
     _ = std::fs::create_dir_all(a.clone());
     _ = std::fs::create_dir_all(b.clone());
 
-    let (mut na, mut nae) = Node::init(a).await?;
-    let (mut nb, mut nbe) = Node::init(b).await?;
+    let (mut na, mut nae) = Node::init(a.clone()).await?;
+    let (mut nb, mut nbe) = Node::init(b.clone()).await?;
     let nacmd = na.get_cmd_api();
     let naque = na.get_query_api();
     let nbcmd = nb.get_cmd_api();
@@ -83,6 +98,13 @@ pub async fn nodes_pair_send_openuri() -> Result<(), Box<dyn std::error::Error>>
     assert_eq!(1, confa.known_peers.len());
     assert_eq!(1, confb.known_peers.len());
 
+    // name the paired node
+    nbcmd.rename_peer(confa.id.clone(), Some("node a".to_string())).await.unwrap();
+    let known = nbque.get_known_peers().await.unwrap();
+    assert_eq!(1, known.len());
+    assert_eq!(confa.id, known[0].metadata.id);
+    assert_eq!("node a", known[0].name());
+
     // start discovery
     nacmd.start_discovery().await.unwrap();
     nbcmd.start_discovery().await.unwrap();
@@ -127,5 +149,334 @@ pub async fn nodes_pair_send_openuri() -> Result<(), Box<dyn std::error::Error>>
         panic!("The wrong response was received")
     };
     assert_eq!(id, confb.id);
+
+    // send a text snippet from node A to node B
+    nacmd.send_text(confb.id.clone(), "otp 123456".to_string()).await.unwrap();
+    let Some(CoreEvent::AppControlUpdate { status: ControlStatus::Waiting, .. }) = nae.recv().await else {
+        panic!("The wrong response was received")
+    };
+    let Some(CoreEvent::AppControl { peer: id, ctl: ControlMessage::Text { text, ask: true }, sid: s }) = nbe.recv().await else {
+        panic!("The wrong response was received")
+    };
+    assert_eq!("otp 123456", text);
+    nbcmd.ctl_accept(id, s).await.unwrap();
+    let Some(CoreEvent::AppControlUpdate { status: ControlStatus::Success, .. }) = nae.recv().await else {
+        panic!("The wrong response was received")
+    };
+
+    // a uri node B doesn't allow is rejected without asking its user
+    nacmd.send_peer(confb.id.clone(), PeerRequest::LaunchUri("file:///etc/passwd".to_string())).await.unwrap();
+    let Some(CoreEvent::AppControlUpdate { status: ControlStatus::Blocked, .. }) = nae.recv().await else {
+        panic!("The wrong response was received")
+    };
+
+    // node B trusts uris from node A & blocks its text
+    let mut trusted = nbque.get_config().await.unwrap();
+    trusted.known_peers.get_mut(&confa.id).unwrap().trust = [(RequestKind::Uri, Rule::Accept), (RequestKind::Text, Rule::Block)].into();
+    nbcmd.set_config(trusted).await.unwrap();
+    nacmd.send_text(confb.id.clone(), "blocked".to_string()).await.unwrap();
+    let Some(CoreEvent::AppControlUpdate { status: ControlStatus::Blocked, .. }) = nae.recv().await else {
+        panic!("The wrong response was received")
+    };
+    nacmd.send_peer(confb.id.clone(), PeerRequest::LaunchUri("https://example.com".to_string())).await.unwrap();
+    let Some(CoreEvent::AppControl { ctl: ControlMessage::LaunchUri { ask: false, .. }, .. }) = nbe.recv().await else {
+        panic!("The wrong response was received")
+    };
+    let Some(CoreEvent::AppControlUpdate { status: ControlStatus::Success, .. }) = nae.recv().await else {
+        panic!("The wrong response was received")
+    };
+
+    // send a file from node A to node B
+    let downloads = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("downloads");
+    _ = std::fs::remove_dir_all(downloads.clone());
+    let mut confb = nbque.get_config().await.unwrap();
+    confb.download_dir = downloads.clone();
+    nbcmd.set_config(confb.clone()).await.unwrap();
+
+    let file = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("file.bin");
+    let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    std::fs::write(&file, &data)?;
+    nacmd.send_file(confb.id.clone(), file.clone()).await.unwrap();
+
+    let (id, s) = loop {
+        match nbe.recv().await {
+            Some(CoreEvent::AppControl { peer, sid, ctl: ControlMessage::ReceiveFile { name, size, ask: true } }) => {
+                assert_eq!("file.bin", name);
+                assert_eq!(data.len() as u64, size);
+                break (peer, sid);
+            }
+            Some(_) => continue,
+            None => panic!("The wrong response was received"),
+        }
+    };
+    assert_eq!(id, confa.id);
+    nbcmd.ctl_accept(id, s).await.unwrap();
+
+    // progress is reported until the host confirms it saved the file & the digests matched
+    let digest: String = ring::digest::digest(&ring::digest::SHA256, &data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let mut transferred = 0;
+    let mut success = false;
+    loop {
+        match nae.recv().await {
+            Some(CoreEvent::TransferProgress { bytes, total, .. }) => {
+                assert_eq!(data.len() as u64, total);
+                transferred = bytes;
+            }
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Success, peer }) => {
+                assert_eq!(peer, confb.id);
+                success = true;
+            }
+            Some(CoreEvent::TransferCompleted { digest: d, path, .. }) => {
+                assert_eq!(digest, d);
+                assert_eq!(file, path);
+                break;
+            }
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Waiting | ControlStatus::Transferring, .. }) => {}
+            x => panic!("The wrong response was received: {:?}", x),
+        }
+    }
+    assert!(success);
+    assert_eq!(data.len() as u64, transferred);
+    assert_eq!(data, std::fs::read(downloads.join("file.bin"))?);
+
+    // resume an interrupted transfer, node B drops what it wrote after its last checkpoint
+    let resumed = downloads.join("resumed.bin");
+    std::fs::write(&resumed, [&data[..100_000], &[0xff; 1000]].concat())?;
+    let manifest = TransferManifest {
+        id: 42,
+        peer: confa.id.clone(),
+        path: resumed.clone(),
+        size: data.len() as u64,
+        completed: std::iter::once(0..100_000).collect(),
+    };
+    std::fs::write(b.join("transfers").join(format!("recv-{}-42.json", confa.id)), serde_json::to_string(&manifest)?)?;
+    let manifest = TransferManifest {
+        peer: confb.id.clone(),
+        path: file.clone(),
+        ..manifest
+    };
+    std::fs::write(a.join("transfers").join("send-42.json"), serde_json::to_string(&manifest)?)?;
+    assert_eq!(vec![42], naque.get_transfers().await.unwrap().iter().map(|t| t.id).collect::<Vec<_>>());
+
+    nacmd.resume_transfer(42).await.unwrap();
+    loop {
+        match nae.recv().await {
+            Some(CoreEvent::TransferProgress { bytes, .. }) => assert!(bytes >= 100_000),
+            Some(CoreEvent::TransferCompleted { digest: d, .. }) => {
+                assert_eq!(digest, d);
+                break;
+            }
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Success | ControlStatus::Transferring, .. }) => {}
+            x => panic!("The wrong response was received: {:?}", x),
+        }
+    }
+    assert_eq!(data, std::fs::read(&resumed)?);
+    assert!(naque.get_transfers().await.unwrap().is_empty());
+
+    // a file which doesn't match its digest is discarded
+    let corrupt = downloads.join("corrupt.bin");
+    std::fs::write(&corrupt, [0xff; 100_000])?;
+    let manifest = TransferManifest {
+        id: 43,
+        path: corrupt.clone(),
+        peer: confa.id.clone(),
+        ..manifest
+    };
+    std::fs::write(b.join("transfers").join(format!("recv-{}-43.json", confa.id)), serde_json::to_string(&manifest)?)?;
+    let manifest = TransferManifest {
+        peer: confb.id.clone(),
+        path: file.clone(),
+        ..manifest
+    };
+    std::fs::write(a.join("transfers").join("send-43.json"), serde_json::to_string(&manifest)?)?;
+
+    nacmd.resume_transfer(43).await.unwrap();
+    loop {
+        match nae.recv().await {
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Corrupted, .. }) => break,
+            Some(CoreEvent::TransferProgress { .. }) => {}
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Transferring, .. }) => {}
+            x => panic!("The wrong response was received: {:?}", x),
+        }
+    }
+    assert!(!corrupt.exists());
+
+    // send a directory from node A to node B, its tree & permissions are recreated
+    let artifacts = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("artifacts");
+    _ = std::fs::remove_dir_all(artifacts.clone());
+    std::fs::create_dir_all(artifacts.join("bin"))?;
+    std::fs::write(artifacts.join("bin").join("tool"), b"#!/bin/sh\n")?;
+    std::fs::write(artifacts.join("README"), &data[..1000])?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(artifacts.join("bin").join("tool"), std::fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("bin/tool", artifacts.join("tool"))?;
+    }
+    nacmd.send_directory(confb.id.clone(), artifacts.clone()).await.unwrap();
+
+    let (id, s) = loop {
+        match nbe.recv().await {
+            Some(CoreEvent::AppControl { peer, sid, ctl: ControlMessage::ReceiveDirectory { name, files, size, ask: true } }) => {
+                assert_eq!("artifacts", name);
+                assert_eq!(2, files);
+                assert_eq!(1010, size);
+                break (peer, sid);
+            }
+            Some(_) => continue,
+            None => panic!("The wrong response was received"),
+        }
+    };
+    nbcmd.ctl_accept(id, s).await.unwrap();
+    loop {
+        match nae.recv().await {
+            Some(CoreEvent::TransferCompleted { path, .. }) => {
+                assert_eq!(artifacts, path);
+                break;
+            }
+            Some(CoreEvent::TransferProgress { .. } | CoreEvent::TransferInterrupted { .. }) => {}
+            Some(CoreEvent::AppControlUpdate { status: ControlStatus::Waiting | ControlStatus::Transferring | ControlStatus::Success, .. }) => {}
+            x => panic!("The wrong response was received: {:?}", x),
+        }
+    }
+    let received = downloads.join("artifacts");
+    assert_eq!(b"#!/bin/sh\n".to_vec(), std::fs::read(received.join("bin").join("tool"))?);
+    assert_eq!(data[..1000].to_vec(), std::fs::read(received.join("README"))?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(received.join("bin").join("tool"))?.permissions().mode();
+        assert_eq!(0o755, mode & 0o777);
+        assert_eq!(std::path::PathBuf::from("bin/tool"), std::fs::read_link(received.join("tool"))?);
+    }
+
+    // sync the clipboard between node A and node B, a change isn't echoed back to where it came from
+    let mut confa = naque.get_config().await.unwrap();
+    confa.clipboard_peers.insert(confb.id.clone());
+    nacmd.set_config(confa.clone()).await.unwrap();
+    let mut confb = nbque.get_config().await.unwrap();
+    confb.clipboard_peers.insert(confa.id.clone());
+    nbcmd.set_config(confb.clone()).await.unwrap();
+    nacmd.clipboard_changed("copied on A".to_string()).await.unwrap();
+    loop {
+        match nbe.recv().await {
+            Some(CoreEvent::Clipboard { peer, text }) => {
+                assert_eq!(confa.id, peer);
+                assert_eq!("copied on A", text);
+                break;
+            }
+            Some(_) => continue,
+            None => panic!("The wrong response was received"),
+        }
+    }
+    nbcmd.clipboard_changed("copied on A".to_string()).await.unwrap();
+    nbcmd.clipboard_changed("copied on B".to_string()).await.unwrap();
+    loop {
+        match nae.recv().await {
+            Some(CoreEvent::Clipboard { peer, text }) => {
+                assert_eq!(confb.id, peer);
+                assert_eq!("copied on B", text);
+                break;
+            }
+            Some(_) => continue,
+            None => panic!("The wrong response was received"),
+        }
+    }
+
+    // unpair node B, which is told to forget node A too
+    nacmd.unpair(confb.id.clone()).await.unwrap();
+    assert!(naque.get_known_peers().await.unwrap().is_empty());
+    assert!(naque.get_discovered_peers().await.unwrap().is_empty());
+    loop {
+        match nbe.recv().await {
+            Some(CoreEvent::Unpaired(peer)) => {
+                assert_eq!(confa.id, peer);
+                break;
+            }
+            Some(_) => continue,
+            None => panic!("The wrong response was received"),
+        }
+    }
+    assert!(nbque.get_known_peers().await.unwrap().is_empty());
+    assert!(nbque.get_config().await.unwrap().clipboard_peers.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn rediscovered_peer_is_stored() -> Result<(), Box<dyn std::error::Error>> {
+    fdcore::secret::mock_store();
+    let d = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("d");
+    _ = std::fs::remove_dir_all(d.clone());
+    _ = std::fs::create_dir_all(d.clone());
+    let paired = PeerMetadata {
+        id: PeerId::from_string("e".repeat(40))?,
+        name: "phone".to_string(),
+        addr: "127.0.0.1:5000".parse()?,
+        ..Default::default()
+    };
+
+    let (nd, mut nde) = Node::init(d.clone()).await?;
+    let ndcmd = nd.get_cmd_api();
+    let ndque = nd.get_query_api();
+    tokio::spawn(nd.start());
+    ndcmd.pair(QrPayload { secret: "123abc7890987654321".to_string(), peer: paired.clone() }).await.unwrap();
+
+    // the paired peer responds to presence with a new name from a new address
+    let moved = PeerMetadata { name: "renamed phone".to_string(), addr: "127.0.0.1:5001".parse()?, ..paired.clone() };
+    let multicast = SocketAddr::V4(SocketAddrV4::new(discovery::DISCOVERY_MULTICAST, 50692));
+    let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast.port()));
+    let (socket, _) = discovery::multicast(&local, &multicast)?;
+    let mut socket = UdpFramed::new(socket, DiscoveryCodec);
+    socket.send((DiscoveryEvent::PresenceResponse(moved.clone()), multicast)).await?;
+    let discovered = loop {
+        match tokio::time::timeout(Duration::from_secs(1), nde.recv()).await {
+            Ok(Some(CoreEvent::Discovered(peer))) if peer.id == paired.id => break peer,
+            Ok(Some(_)) => continue,
+            _ => panic!("The peer was not discovered"),
+        }
+    };
+
+    // the name is stored but the address isn't replaced before a connection reached the peer there
+    assert_eq!(moved.name, discovered.name);
+    assert_eq!(paired.addr, discovered.addr);
+    let known = ndque.get_known_peers().await.unwrap();
+    assert_eq!(1, known.len());
+    assert_eq!(moved.name, known[0].metadata.name);
+    assert_eq!(paired.addr, known[0].metadata.addr);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn pairing_secrets_survive_restart() -> Result<(), Box<dyn std::error::Error>> {
+    let c = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("c");
+    _ = std::fs::remove_dir_all(c.clone());
+    _ = std::fs::create_dir_all(c.clone());
+    let vault = || Secrets::Vault(VaultKey::Passphrase("correct horse".to_string()));
+    let paired = PeerMetadata { id: PeerId::from_string("c".repeat(40))?, ..Default::default() };
+    let lost = PeerMetadata { id: PeerId::from_string("d".repeat(40))?, ..Default::default() };
+
+    let (nc, _) = Node::init_with(c.clone(), vault()).await?;
+    let nccmd = nc.get_cmd_api();
+    let ncque = nc.get_query_api();
+    let task = tokio::spawn(nc.start());
+    nccmd.pair(QrPayload { secret: "123abc7890987654321".to_string(), peer: paired.clone() }).await.unwrap();
+    // a peer whose secret was lost, e.g. the config was copied to another host
+    let mut conf = ncque.get_config().await.unwrap();
+    conf.known_peers.paired(lost.clone());
+    nccmd.set_config(conf).await.unwrap();
+    task.abort();
+    _ = task.await;
+
+    // only the peer without a secret is reported when the node starts again
+    let (_nc, mut nce) = Node::init_with(c.clone(), vault()).await?;
+    let Ok(CoreEvent::SecretMissing { peer, error: None }) = nce.try_recv() else {
+        panic!("The wrong response was received")
+    };
+    assert_eq!(lost.id, peer);
+    assert!(nce.try_recv().is_err());
     Ok(())
 }
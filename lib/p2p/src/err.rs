@@ -43,13 +43,21 @@ pub enum ConnError {
     #[error("The peer was not found")]
     NotFound,
 
-    /// The local peer is already connected
-    #[error("A connection already exists")]
-    Dup,
+    /// The remote peer rejected the request
+    #[error("The remote peer rejected the request")]
+    Rejected,
 
     /// The remote peer had no connectable addresses
     #[error("No connectable addresses")]
     Addr,
+
+    /// The remote peer speaks an incompatible version of the protocol
+    #[error("The remote peer speaks an incompatible protocol version")]
+    Incompatible,
+
+    /// The TLS session with the remote peer could not be established
+    #[error("The TLS session failed: {0}")]
+    Tls(#[from] rustls::Error),
 }
 
 impl From<ring::error::Unspecified> for ConnError {
@@ -96,6 +104,14 @@ pub enum ParseError {
     /// The peer id is not valid
     #[error("The peer id {0} is not valid")]
     Id(#[from] IdError),
+
+    /// A text field is not valid utf8
+    #[error("The text is not valid utf8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    /// The frame is shorter than the fields it claims to hold
+    #[error("The frame is too short for its fields")]
+    Truncated,
 }
 
 impl<T> From<num_enum::TryFromPrimitiveError<T>> for ParseError
@@ -8,6 +8,24 @@ pub enum P2pEvent {
     /// A peer was discovered
     PeerDiscovered(peer::PeerMetadata),
 
+    /// A peer which is not paired was discovered, it can be paired with but not connected to
+    NearbyDiscovered(peer::PeerMetadata),
+
+    /// A nearby peer requested to pair
+    PairingRequested(crate::pairing::PairingRequest),
+
+    /// Pairing with a peer completed, the secret is shared by both peers and should be persisted
+    PeerPaired {
+        peer: peer::PeerMetadata,
+        secret: String,
+    },
+
+    /// A discovered peer's metadata changed, or a connection reached it at a new address
+    PeerUpdated(peer::PeerMetadata),
+
+    /// A discovered or nearby peer has not been seen within the ttl
+    PeerLost(peer::PeerId),
+
     /// A peer connected
     PeerConnected(peer::Peer),
 
@@ -38,14 +56,7 @@ impl crate::proto::Frame for DiscoveryEvent {
     fn len(&self) -> u16 {
         match self {
             DiscoveryEvent::PresenceRequest(_) => 1 + 4,
-            DiscoveryEvent::PresenceResponse(meta) => {
-                1 + 2
-                    + 2
-                    + u16::try_from(meta.name.len()).unwrap()
-                    + 40
-                    + 2
-                    + u16::try_from(meta.addr.to_string().len()).unwrap()
-            }
+            DiscoveryEvent::PresenceResponse(meta) => 1 + crate::proto::metadata_len(meta),
         }
     }
 }
@@ -1,8 +1,9 @@
 use futures_util::{SinkExt, StreamExt};
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     net::{TcpListener, UdpSocket},
     sync::mpsc::UnboundedReceiver,
+    time::{interval, MissedTickBehavior},
 };
 use tokio_util::udp::UdpFramed;
 use tracing::{debug, error};
@@ -10,9 +11,12 @@ use tracing::{debug, error};
 use crate::{
     event::{DiscoveryEvent, InternalEvent},
     manager::P2pManager,
-    proto::DiscoveryCodec,
+    proto::{DiscoveryCodec, MessageType},
 };
 
+/// How often discovered peers are checked for expiry
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(500);
+
 pub(crate) async fn p2p_event_loop(
     manager: Arc<P2pManager>,
     mut internal_channel: UnboundedReceiver<InternalEvent>,
@@ -21,6 +25,8 @@ pub(crate) async fn p2p_event_loop(
     discovery: (UdpSocket, SocketAddr),
 ) {
     let (mut udp_tx, mut udp_rx) = UdpFramed::new(discovery.0, DiscoveryCodec).split();
+    let mut expiry = interval(EXPIRY_INTERVAL);
+    expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         tokio::select! {
@@ -30,6 +36,7 @@ pub(crate) async fn p2p_event_loop(
                     break;
                 };
             },
+            _ = expiry.tick() => manager.expire_peers(),
             stream_event = listener.accept() => {
                 let Ok((stream, addr)) = stream_event else {
                    continue;
@@ -37,8 +44,18 @@ pub(crate) async fn p2p_event_loop(
                 debug!("Remote peer attempting to connect at {:?}", &addr);
                 let manager = manager.clone();
                 tokio::spawn(async move {
-                    if let Ok(peer) = crate::net::accept(&manager, stream).await {
-                        manager.handle_new_connection(peer);
+                    match crate::net::peek_message_type(&stream).await {
+                        Ok(MessageType::Pair) => {
+                            if let Ok((peer, secret)) = crate::pair::accept(&manager, stream).await {
+                                manager.handle_peer_paired(peer, secret);
+                            }
+                        }
+                        Ok(_) => {
+                            if let Ok(peer) = crate::net::accept(&manager, stream).await {
+                                manager.handle_new_connection(peer);
+                            }
+                        }
+                        Err(e) => error!("Failed to read the first message: {:?}", e),
                     }
                 });
             },
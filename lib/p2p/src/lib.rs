@@ -4,7 +4,11 @@ pub mod event;
 mod event_loop;
 mod hmac;
 pub mod manager;
+mod mux;
 mod net;
+mod pair;
 pub mod pairing;
 pub mod peer;
 pub mod proto;
+mod spake;
+mod tls;
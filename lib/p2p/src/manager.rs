@@ -2,9 +2,11 @@ use std::{
     collections::HashSet,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap};
+use rustls::{Certificate, PrivateKey};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::mpsc,
@@ -15,7 +17,8 @@ use crate::{
     discovery, err,
     event::*,
     event_loop,
-    peer::{DeviceType, Peer, PeerCandidate, PeerId, PeerMetadata},
+    pairing::{PairingAuthenticator, PairingRequest},
+    peer::{ConnectionType, DeviceType, Identity, Peer, PeerCandidate, PeerId, PeerMetadata},
 };
 
 pub struct P2pManager {
@@ -23,8 +26,9 @@ pub struct P2pManager {
     /// PeerId is the unique identifier of the current peer.
     pub(crate) id: PeerId,
 
-    // /// identity is the TLS identity of the current peer.
-    // pub(crate) identity: (Certificate, PrivateKey),
+    /// identity is the TLS identity of the current peer.
+    pub(crate) identity: (Certificate, PrivateKey),
+
     /// The metadata of the current peer
     pub(crate) metadata: PeerMetadata,
 
@@ -33,10 +37,22 @@ pub struct P2pManager {
     known_peers: DashMap<PeerId, PeerCandidate>,
 
     /// discovered_peers contains a list of all peers which have been discovered by any discovery mechanism.
-    discovered_peers: DashMap<PeerId, PeerCandidate>,
+    discovered_peers: DashMap<PeerId, Discovered>,
+
+    /// nearby_peers contains the peers which have been discovered but are not paired, they can not be connected to.
+    nearby_peers: DashMap<PeerId, Nearby>,
+
+    /// how long a discovered peer is kept after it was last seen
+    peer_ttl: Duration,
 
-    /// connected_peers
-    connected_peers: DashSet<PeerId>,
+    /// connected_peers is the pool of live connections, which are handed back out instead of reconnecting.
+    connected_peers: DashMap<PeerId, Pooled>,
+
+    /// how long a connection without any open streams is kept alive
+    pub(crate) idle_timeout: Duration,
+
+    /// the maximum number of connections to keep in the pool
+    max_connections: usize,
 
     /// channel to send Discovery events
     discovery_channel: mpsc::UnboundedSender<DiscoveryEvent>,
@@ -52,17 +68,46 @@ pub struct P2pManager {
 }
 
 pub struct P2pConfig {
-    pub id: PeerId,
+    /// the identity of the current peer, the [PeerId] is derived from it
+    pub identity: Identity,
     pub device: DeviceType,
     pub name: String,
     pub multicast: SocketAddr,
     pub p2p_addr: SocketAddr,
+    /// how long a connection without any open streams is kept alive
+    pub idle_timeout: Duration,
+    /// the maximum number of connections kept alive at once, the least recently used connection is closed
+    /// when a new connection would exceed it
+    pub max_connections: usize,
+    /// how long a discovered peer is kept without a presence response before it is considered lost
+    pub peer_ttl: Duration,
+}
+
+/// A peer found by discovery
+struct Discovered {
+    candidate: PeerCandidate,
+    last_seen: Instant,
+}
+
+/// An unpaired peer found by discovery
+struct Nearby {
+    metadata: PeerMetadata,
+    last_seen: Instant,
+}
+
+/// A live connection in the pool
+struct Pooled {
+    peer: Peer,
+    last_used: Instant,
 }
 
 impl P2pManager {
     pub async fn new(
         config: P2pConfig,
     ) -> std::io::Result<(Arc<Self>, mpsc::UnboundedReceiver<P2pEvent>)> {
+        let identity = config.identity.into_rustls();
+        let id = PeerId::from_cert(&identity.0);
+
         // setup discovery
         let local = SocketAddr::V4(SocketAddrV4::new(
             Ipv4Addr::LOCALHOST,
@@ -72,15 +117,11 @@ impl P2pManager {
 
         // setup tcp listener
         let listener = TcpListener::bind(config.p2p_addr).await?;
-        debug!(
-            "Peer {} listening on {}",
-            config.id.clone(),
-            listener.local_addr()?
-        );
+        debug!("Peer {} listening on {}", id, listener.local_addr()?);
 
         // setup metadata
         let metadata = PeerMetadata {
-            id: config.id.clone(),
+            id: id.clone(),
             typ: config.device,
             name: config.name,
             addr: listener.local_addr()?,
@@ -91,11 +132,16 @@ impl P2pManager {
         let discovery_channel = mpsc::unbounded_channel();
 
         let this = Arc::new(Self {
-            id: config.id,
+            id,
+            identity,
             metadata,
             known_peers: DashMap::new(),
             discovered_peers: DashMap::new(),
-            connected_peers: DashSet::new(),
+            nearby_peers: DashMap::new(),
+            peer_ttl: config.peer_ttl,
+            connected_peers: DashMap::new(),
+            idle_timeout: config.idle_timeout,
+            max_connections: config.max_connections,
             discovery_channel: discovery_channel.0,
             internal_channel: internal_channel.0,
             app_channel: app_channel.0,
@@ -118,11 +164,53 @@ impl P2pManager {
         self.discovery_channel.is_closed()
     }
 
+    /// application calls this to pair with a nearby peer, the remote peer's user has to accept the request
+    pub async fn request_pairing(self: &Arc<Self>, id: &PeerId) -> Result<(), err::ConnError> {
+        self.pair(id, None).await
+    }
+
+    /// application calls this to pair with a nearby peer using a code shown to the user, see [crate::pairing::generate_code].
+    /// The remote peer's user has to enter the same code to accept the request.
+    pub async fn request_pairing_with_code(
+        self: &Arc<Self>,
+        id: &PeerId,
+        code: &str,
+    ) -> Result<(), err::ConnError> {
+        self.pair(id, Some(code)).await
+    }
+
+    async fn pair(self: &Arc<Self>, id: &PeerId, code: Option<&str>) -> Result<(), err::ConnError> {
+        let Some(peer) = self.nearby_peers.get(id).map(|p| p.metadata.clone()) else {
+            return Err(err::ConnError::NotFound);
+        };
+        let conn = TcpStream::connect(peer.addr).await.map_err(|e| {
+            error!("Attempt to connect to address {:?} failed {:?}", peer.addr, e);
+            err::ConnError::Addr
+        })?;
+        let secret = crate::pair::request(self, conn, &peer, code).await?;
+        self.handle_peer_paired(peer, secret);
+        Ok(())
+    }
+
+    /// application calls this to close the connection to a peer
+    pub fn disconnect(&self, id: &PeerId) {
+        if let Some((_, pooled)) = self.connected_peers.remove(id) {
+            pooled.peer.close();
+        }
+    }
+
     /// called by the application to populate already known peers
     pub fn add_known_peer(&self, peer: PeerCandidate) {
+        self.nearby_peers.remove(&peer.id);
         self.known_peers.insert(peer.id.clone(), peer);
     }
 
+    /// called by the application to forget a known peer, it can't connect anymore until it's paired again
+    pub fn remove_known_peer(&self, id: &PeerId) {
+        self.known_peers.remove(id);
+        self.discovered_peers.remove(id);
+    }
+
     // called by the application to send a presenct request
     pub fn request_presence(&self) {
         if let Err(e) = self
@@ -142,6 +230,14 @@ impl P2pManager {
 
     pub fn get_discovered_peers(&self) -> Vec<PeerMetadata> {
         self.discovered_peers
+            .iter()
+            .map(|p| p.candidate.metadata.clone())
+            .collect()
+    }
+
+    /// application calls this to list the peers nearby which are not paired
+    pub fn get_nearby_peers(&self) -> Vec<PeerMetadata> {
+        self.nearby_peers
             .iter()
             .map(|p| p.metadata.clone())
             .collect()
@@ -152,20 +248,29 @@ impl P2pManager {
     }
 
     pub fn is_connected(&self, id: &PeerId) -> bool {
-        self.connected_peers.contains(id)
+        self.connected_peers
+            .get(id)
+            .is_some_and(|p| !p.peer.is_closed())
     }
 
-    /// application calls this to connect to a peer
+    /// application calls this to connect to a peer, an existing connection to the peer is reused if there is one
     pub async fn connect_to_peer(self: &Arc<Self>, id: &PeerId) -> Result<Peer, err::ConnError> {
-        if self.connected_peers.contains(id) {
-            return Err(err::ConnError::Dup);
+        if let Some(mut pooled) = self.connected_peers.get_mut(id) {
+            if !pooled.peer.is_closed() {
+                debug!("reusing the connection to peer {}", id);
+                pooled.last_used = Instant::now();
+                return Ok(pooled.peer.clone());
+            }
         }
-        let Some(candidate) = self.discovered_peers.get(id) else {
-            return Err(err::ConnError::NotFound)
+        // a known peer which was lost, e.g. because discovery stopped, is dialed where it was last seen
+        let Some(candidate) = self.get_peer_candidate(id).filter(|c| !c.addrs.is_empty()) else {
+            return Err(err::ConnError::NotFound);
         };
 
         // let peer = candidate.clone();
 
+        // an address from an unauthenticated presence response may belong to another host, the next one is tried
+        let mut failed = err::ConnError::Addr;
         for addr in &candidate.addrs {
             match TcpStream::connect(addr).await {
                 Err(e) => {
@@ -173,20 +278,114 @@ impl P2pManager {
                 }
                 Ok(conn) => {
                     debug!("Attempting to connect to {:?}", addr);
-                    let peer = crate::net::connect(self, conn, &candidate).await?;
-                    self.connected_peers.insert(id.clone());
-                    return Ok(peer);
+                    match crate::net::connect(self, conn, &candidate).await {
+                        Ok(peer) => {
+                            self.reached(id, *addr);
+                            return Ok(self.pool(peer));
+                        }
+                        Err(e) => {
+                            error!("Handshake with address {:?} failed {:?}", addr, e);
+                            failed = e;
+                        }
+                    }
                 }
             }
         }
-        Err(err::ConnError::Addr)
+        Err(failed)
     }
 
     // [START] Crate methods the event loop can call
 
+    /// keep a connection in the pool, closing the least recently used connection if the pool is full. Returns the
+    /// connection which is kept for the peer, which is an older one if the new connection lost the tie-break.
+    fn pool(&self, peer: Peer) -> Peer {
+        let id = peer.id.clone();
+        let kept = match self.connected_peers.entry(id.clone()) {
+            // both peers connected at the same time, both keep the connection dialed by the lower id so they agree
+            Entry::Occupied(old)
+                if !old.get().peer.is_closed()
+                    && self.dialer(&old.get().peer) < self.dialer(&peer) =>
+            {
+                debug!("keeping the connection to {} dialed by the lower id", id);
+                peer.close();
+                old.get().peer.clone()
+            }
+            // a new connection replaces an older one dialed by the same peer or one which is closed
+            Entry::Occupied(mut old) => {
+                let old = old.insert(Pooled {
+                    peer: peer.clone(),
+                    last_used: Instant::now(),
+                });
+                old.peer.close();
+                peer
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Pooled {
+                    peer: peer.clone(),
+                    last_used: Instant::now(),
+                });
+                peer
+            }
+        };
+        while self.connected_peers.len() > self.max_connections {
+            let lru = self
+                .connected_peers
+                .iter()
+                .filter(|p| *p.key() != id)
+                .min_by_key(|p| p.last_used)
+                .map(|p| p.key().clone());
+            let Some(lru) = lru else { break };
+            debug!("the connection pool is full, closing {}", lru);
+            self.disconnect(&lru);
+        }
+        kept
+    }
+
+    /// the peer presented its pinned certificate at the address, so the addresses it was seen at before are stale
+    fn reached(&self, id: &PeerId, addr: SocketAddr) {
+        let reach = |candidate: &mut PeerCandidate| {
+            candidate.addrs = HashSet::from([addr]);
+            let moved = candidate.metadata.addr != addr;
+            candidate.metadata.addr = addr;
+            moved.then(|| candidate.metadata.clone())
+        };
+        let moved = self
+            .discovered_peers
+            .get_mut(id)
+            .and_then(|mut discovered| reach(&mut discovered.candidate));
+        let moved = self
+            .known_peers
+            .get_mut(id)
+            .and_then(|mut known| reach(&mut known))
+            .or(moved);
+        if let Some(metadata) = moved {
+            debug!("peer {} moved to {}", id, addr);
+            if self
+                .app_channel
+                .send(P2pEvent::PeerUpdated(metadata))
+                .is_err()
+            {
+                error!("failed to send PeerUpdated event to the application");
+            }
+        }
+    }
+
+    /// the id of the peer which dialed the connection
+    fn dialer<'a>(&'a self, peer: &'a Peer) -> &'a PeerId {
+        match peer.conn_type {
+            ConnectionType::Client => &self.id,
+            ConnectionType::Server => &peer.id,
+        }
+    }
+
     /// called by a connected peer's connection handler when closing
     pub(crate) fn peer_disconnected(self: &Arc<Self>, id: &PeerId) {
-        self.connected_peers.remove(id);
+        // connections which were replaced by a newer connection close silently
+        if self.is_connected(id) {
+            return;
+        }
+        self.connected_peers
+            .remove_if(id, |_, pooled| pooled.peer.is_closed());
         if self
             .app_channel
             .send(P2pEvent::PeerDisconnected(id.clone()))
@@ -200,7 +399,7 @@ impl P2pManager {
     pub(crate) fn get_peer_candidate(&self, id: &PeerId) -> Option<PeerCandidate> {
         self.discovered_peers
             .get(id)
-            .map(|p| p.value().clone())
+            .map(|p| p.candidate.clone())
             .or(self.known_peers.get(id).map(|p| p.value().clone()))
     }
 
@@ -215,17 +414,43 @@ impl P2pManager {
     /// event loop calls this to inform manager a peer was discovered
     pub(crate) fn handle_peer_discovered(&self, peer: PeerMetadata) {
         let id = peer.id.clone();
-        if !self.connected_peers.contains(&id) && !self.discovered_peers.contains_key(&id) {
-            // TODO: fix not removing
-            if let Some(known) = self.known_peers.remove(&id) {
-                let mut candidate = PeerCandidate {
-                    id: id.clone(),
-                    metadata: peer.clone(),
-                    addrs: HashSet::new(),
-                    auth: known.1.auth,
+        if let Some(mut discovered) = self.discovered_peers.get_mut(&id) {
+            discovered.last_seen = Instant::now();
+            // presence responses aren't authenticated, so their address is only tried alongside the others. It
+            // replaces them once a connection to it succeeds
+            let fresh = discovered.candidate.addrs.insert(peer.addr);
+            let peer = PeerMetadata {
+                addr: discovered.candidate.metadata.addr,
+                ..peer
+            };
+            let updated = discovered.candidate.metadata != peer;
+            if fresh || updated {
+                discovered.candidate.metadata = peer.clone();
+                let candidate = discovered.candidate.clone();
+                drop(discovered);
+                self.known_peers.insert(id, candidate);
+            }
+            if updated {
+                debug!("discovered peer is updated");
+                if self.app_channel.send(P2pEvent::PeerUpdated(peer)).is_err() {
+                    error!("failed to send PeerUpdated event to the application");
                 };
+            }
+        } else {
+            // TODO: fix not removing
+            if let Some((_, mut candidate)) = self.known_peers.remove(&id) {
                 candidate.addrs.insert(peer.addr);
-                self.discovered_peers.insert(id.clone(), candidate.clone());
+                candidate.metadata = PeerMetadata {
+                    addr: candidate.metadata.addr,
+                    ..peer
+                };
+                self.discovered_peers.insert(
+                    id.clone(),
+                    Discovered {
+                        candidate: candidate.clone(),
+                        last_seen: Instant::now(),
+                    },
+                );
                 self.known_peers.insert(id, candidate.clone());
                 debug!("discovered peer is recorded");
                 if self
@@ -235,7 +460,90 @@ impl P2pManager {
                 {
                     error!("failed to send PeerDiscovered event to the application");
                 };
+            } else {
+                self.handle_nearby_peer(peer);
+            }
+        }
+    }
+
+    /// record a peer which is not paired so the application can offer to pair with it
+    fn handle_nearby_peer(&self, peer: PeerMetadata) {
+        if let Some(mut nearby) = self.nearby_peers.get_mut(&peer.id) {
+            nearby.metadata = peer;
+            nearby.last_seen = Instant::now();
+            return;
+        }
+        self.nearby_peers.insert(
+            peer.id.clone(),
+            Nearby {
+                metadata: peer.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+        debug!("nearby peer is recorded");
+        if self
+            .app_channel
+            .send(P2pEvent::NearbyDiscovered(peer))
+            .is_err()
+        {
+            error!("failed to send NearbyDiscovered event to the application");
+        };
+    }
+
+    /// event loop calls this periodically to forget peers, paired or nearby, which have not been seen within the ttl.
+    /// Connected peers are kept as they are evidently still reachable, a lost known peer can still be connected to
+    /// at the address it was last seen at.
+    pub(crate) fn expire_peers(&self) {
+        let mut lost = Vec::new();
+        self.discovered_peers.retain(|id, discovered| {
+            if discovered.last_seen.elapsed() < self.peer_ttl || self.is_connected(id) {
+                return true;
             }
+            lost.push(id.clone());
+            false
+        });
+        self.nearby_peers.retain(|id, nearby| {
+            if nearby.last_seen.elapsed() < self.peer_ttl {
+                return true;
+            }
+            lost.push(id.clone());
+            false
+        });
+        for id in lost {
+            debug!("discovered peer {} was lost", id);
+            if self.app_channel.send(P2pEvent::PeerLost(id)).is_err() {
+                error!("failed to send PeerLost event to the application");
+            }
+        }
+    }
+
+    /// pairing handshake calls this to ask the application whether to pair with a peer
+    pub(crate) fn handle_pairing_request(&self, request: PairingRequest) {
+        if self
+            .app_channel
+            .send(P2pEvent::PairingRequested(request))
+            .is_err()
+        {
+            error!("failed to send PairingRequested event to the application");
+        }
+    }
+
+    /// pairing handshake calls this once a peer is paired, it becomes a known peer
+    pub(crate) fn handle_peer_paired(&self, peer: PeerMetadata, secret: String) {
+        let auth = match PairingAuthenticator::new(secret.clone().into_bytes()) {
+            Ok(auth) => auth,
+            Err(e) => {
+                error!("failed to create the authenticator for the paired peer: {:?}", e);
+                return;
+            }
+        };
+        self.add_known_peer(PeerCandidate::new(&peer, auth));
+        if self
+            .app_channel
+            .send(P2pEvent::PeerPaired { peer, secret })
+            .is_err()
+        {
+            error!("failed to send PeerPaired event to the application");
         }
     }
 
@@ -253,8 +561,11 @@ impl P2pManager {
 
     /// event loop calls this to inform manager a peer is now connected
     pub(crate) fn handle_new_connection(&self, peer: Peer) {
-        let id = peer.id.clone();
-        self.connected_peers.insert(id);
+        let kept = self.pool(peer.clone());
+        if kept.conn_type != peer.conn_type {
+            // the connection this peer dialed is kept & was already handed to the application
+            return;
+        }
         if self
             .app_channel
             .send(P2pEvent::PeerConnected(peer))
@@ -0,0 +1,507 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::{mpsc, oneshot, Semaphore},
+    time::{sleep, Instant},
+};
+use tokio_util::codec::Framed;
+use tracing::{debug, error};
+
+use crate::{
+    peer::ConnectionType,
+    proto::{Stream, StreamCodec, MAX_DATA_LEN},
+};
+
+/// The amount of data a writer may send on a stream before the reader has to grant more
+const WINDOW: u32 = 256 * 1024;
+
+/// The size of the buffer between the application and each stream
+const BUFFER: usize = 64 * 1024;
+
+/// Commands sent by a [crate::peer::Peer] to the task multiplexing its connection
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// open a new outbound stream
+    Open(oneshot::Sender<DuplexStream>),
+
+    /// close the connection along with all of its streams
+    Close,
+}
+
+/// The state of a single stream which is tracked by the multiplexer
+struct StreamState {
+    /// forwards data sent by the remote peer to the stream, dropped once the remote is done writing
+    inbound: Option<mpsc::UnboundedSender<Bytes>>,
+
+    /// the credit available to send data to the remote peer
+    credit: Arc<Semaphore>,
+
+    /// the credit the remote peer has left to send data to this peer, a stream sending beyond it is reset
+    granted: u32,
+
+    /// the local peer is done writing
+    closed: bool,
+}
+
+/// continuously running handler which multiplexes streams over a single connection until either the connection
+/// closes, every handle to the connection is dropped or the connection has no open streams for the idle timeout
+pub(crate) async fn run<T>(
+    conn: T,
+    conn_type: ConnectionType,
+    idle_timeout: Duration,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    incoming: mpsc::UnboundedSender<DuplexStream>,
+) where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut sink, mut source) = Framed::new(conn, StreamCodec).split();
+    let (outbound_tx, mut outbound) = mpsc::unbounded_channel();
+    let mut streams = HashMap::new();
+    let idle = sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    // clients open odd streams & servers open even streams so ids never collide
+    let mut next_id: u32 = match conn_type {
+        ConnectionType::Client => 1,
+        ConnectionType::Server => 2,
+    };
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let reply = match command {
+                    None => {
+                        debug!("all handles to the connection were dropped");
+                        break;
+                    }
+                    Some(Command::Close) => {
+                        debug!("closing the connection");
+                        break;
+                    }
+                    Some(Command::Open(reply)) => reply,
+                };
+                let id = next_id;
+                next_id += 2;
+                if let Err(e) = sink.send(Stream::Open(id)).await {
+                    error!("failed to open stream {}: {:?}", id, e);
+                    break;
+                }
+                let stream = open(id, &mut streams, &outbound_tx);
+                if reply.send(stream).is_err() {
+                    debug!("stream {} was opened but nobody is waiting for it", id);
+                }
+            },
+            frame = outbound.recv() => {
+                // the sender is owned by this task so the channel is never closed
+                let Some(frame) = frame else { break };
+                match &frame {
+                    Stream::Close(id) => {
+                        if let Some(state) = streams.get_mut(id) {
+                            state.closed = true;
+                            if state.inbound.is_none() {
+                                streams.remove(id);
+                            }
+                        }
+                    }
+                    Stream::Reset(id) => {
+                        if let Some(state) = streams.remove(id) {
+                            state.credit.close();
+                        }
+                    }
+                    Stream::Window(id, credit) => {
+                        if let Some(state) = streams.get_mut(id) {
+                            state.granted = state.granted.saturating_add(*credit);
+                        }
+                    }
+                    _ => {}
+                }
+                if let Err(e) = sink.send(frame).await {
+                    error!("failed to write to the connection: {:?}", e);
+                    break;
+                }
+            },
+            _ = &mut idle, if streams.is_empty() => {
+                debug!("the connection has been idle for {:?}", idle_timeout);
+                break;
+            },
+            frame = source.next() => {
+                let frame = match frame {
+                    None => {
+                        debug!("the remote peer closed the connection");
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        error!("failed to read from the connection: {:?}", e);
+                        break;
+                    }
+                    Some(Ok(frame)) => frame,
+                };
+                match frame {
+                    Stream::Open(id) => {
+                        // an id of this peer's parity would collide with a stream it opens later
+                        if id % 2 == next_id % 2 {
+                            error!("remote peer opened the stream {} with a local id", id);
+                            continue;
+                        }
+                        if streams.contains_key(&id) {
+                            error!("remote peer opened the duplicate stream {}", id);
+                            continue;
+                        }
+                        let stream = open(id, &mut streams, &outbound_tx);
+                        if incoming.send(stream).is_err() {
+                            debug!("no one is accepting streams, resetting stream {}", id);
+                            streams.remove(&id);
+                            _ = outbound_tx.send(Stream::Reset(id));
+                        }
+                    }
+                    Stream::Data(id, data) => {
+                        let Some(state) = streams.get_mut(&id) else { continue };
+                        let Some(granted) = state.granted.checked_sub(data.len() as u32) else {
+                            error!("remote peer sent beyond the window of stream {}, resetting it", id);
+                            reset(id, &mut streams, &outbound_tx);
+                            continue;
+                        };
+                        state.granted = granted;
+                        if let Some(inbound) = state.inbound.as_ref() {
+                            _ = inbound.send(data);
+                        }
+                    }
+                    Stream::Window(id, credit) => {
+                        let Some(state) = streams.get(&id) else { continue };
+                        let total = state.credit.available_permits().checked_add(credit as usize);
+                        let overflows = match total {
+                            Some(total) => total > Semaphore::MAX_PERMITS,
+                            None => true,
+                        };
+                        if overflows {
+                            error!("remote peer granted too much credit on stream {}, resetting it", id);
+                            reset(id, &mut streams, &outbound_tx);
+                            continue;
+                        }
+                        state.credit.add_permits(credit as usize);
+                    }
+                    Stream::Close(id) => {
+                        if let Some(state) = streams.get_mut(&id) {
+                            state.inbound = None;
+                            if state.closed {
+                                streams.remove(&id);
+                            }
+                        }
+                    }
+                    Stream::Reset(id) => {
+                        if let Some(state) = streams.remove(&id) {
+                            state.credit.close();
+                        }
+                    }
+                }
+            },
+        }
+
+        // restart the idle timer while streams are open
+        if !streams.is_empty() {
+            idle.as_mut().reset(Instant::now() + idle_timeout);
+        }
+    }
+
+    // wake up any writers still waiting on credit, dropping the senders ends the readers
+    for (_, state) in streams.drain() {
+        state.credit.close();
+    }
+}
+
+/// abort a stream the remote peer broke the protocol on
+fn reset(
+    id: u32,
+    streams: &mut HashMap<u32, StreamState>,
+    outbound: &mpsc::UnboundedSender<Stream>,
+) {
+    if let Some(state) = streams.remove(&id) {
+        state.credit.close();
+    }
+    _ = outbound.send(Stream::Reset(id));
+}
+
+/// create the state for a new stream & spawn the tasks which move its data
+fn open(
+    id: u32,
+    streams: &mut HashMap<u32, StreamState>,
+    outbound: &mpsc::UnboundedSender<Stream>,
+) -> DuplexStream {
+    let (app, transport) = tokio::io::duplex(BUFFER);
+    let (reader, writer) = tokio::io::split(transport);
+    let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+    let credit = Arc::new(Semaphore::new(WINDOW as usize));
+
+    tokio::spawn(write_remote(id, reader, credit.clone(), outbound.clone()));
+    tokio::spawn(write_local(id, writer, inbound_rx, outbound.clone()));
+    streams.insert(
+        id,
+        StreamState {
+            inbound: Some(inbound_tx),
+            credit,
+            granted: WINDOW,
+            closed: false,
+        },
+    );
+    app
+}
+
+/// move data written by the application to the remote peer, as long as there is credit
+async fn write_remote(
+    id: u32,
+    mut reader: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    outbound: mpsc::UnboundedSender<Stream>,
+) {
+    let mut buffer = vec![0; MAX_DATA_LEN];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => {
+                _ = outbound.send(Stream::Close(id));
+                break;
+            }
+            Ok(n) => {
+                let Ok(permits) = credit.acquire_many(n as u32).await else {
+                    debug!("stream {} was reset", id);
+                    break;
+                };
+                permits.forget();
+                if outbound
+                    .send(Stream::Data(id, Bytes::copy_from_slice(&buffer[..n])))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("error reading stream {} from the application: {:?}", id, e);
+                _ = outbound.send(Stream::Reset(id));
+                break;
+            }
+        }
+    }
+}
+
+/// move data sent by the remote peer to the application, granting more credit as it is consumed
+async fn write_local(
+    id: u32,
+    mut writer: WriteHalf<DuplexStream>,
+    mut inbound: mpsc::UnboundedReceiver<Bytes>,
+    outbound: mpsc::UnboundedSender<Stream>,
+) {
+    while let Some(data) = inbound.recv().await {
+        if writer.write_all(&data).await.is_err() {
+            debug!("the application dropped stream {}", id);
+            _ = outbound.send(Stream::Reset(id));
+            return;
+        }
+        _ = outbound.send(Stream::Window(id, data.len() as u32));
+    }
+    _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::{mpsc, oneshot},
+        time::timeout,
+    };
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use super::{run, Command, BUFFER, WINDOW};
+    use crate::{
+        peer::ConnectionType,
+        proto::{Stream, StreamCodec, MAX_DATA_LEN},
+    };
+
+    struct Side {
+        commands: mpsc::UnboundedSender<Command>,
+        incoming: mpsc::UnboundedReceiver<DuplexStream>,
+    }
+
+    impl Side {
+        async fn open(&self) -> DuplexStream {
+            let (tx, rx) = oneshot::channel();
+            self.commands.send(Command::Open(tx)).unwrap();
+            rx.await.unwrap()
+        }
+    }
+
+    fn side(conn: DuplexStream, conn_type: ConnectionType, idle_timeout: Duration) -> Side {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(run(conn, conn_type, idle_timeout, commands_rx, incoming_tx));
+        Side { commands, incoming }
+    }
+
+    /// create both sides of a multiplexed connection over an in-memory stream
+    fn connect_with_timeout(idle_timeout: Duration) -> (Side, Side) {
+        let (client, server) = tokio::io::duplex(1024);
+        (
+            side(client, ConnectionType::Client, idle_timeout),
+            side(server, ConnectionType::Server, idle_timeout),
+        )
+    }
+
+    fn connect() -> (Side, Side) {
+        connect_with_timeout(Duration::from_secs(60))
+    }
+
+    /// a server side & the raw frames of a client which doesn't follow the protocol
+    fn connect_raw() -> (Framed<DuplexStream, StreamCodec>, Side) {
+        let (client, server) = tokio::io::duplex(1024);
+        (
+            Framed::new(client, StreamCodec),
+            side(server, ConnectionType::Server, Duration::from_secs(60)),
+        )
+    }
+
+    #[tokio::test]
+    async fn streams_are_independent() {
+        let (mut client, mut server) = connect();
+
+        let mut a = client.open().await;
+        let mut b = server.open().await;
+        let mut remote_a = server.incoming.recv().await.unwrap();
+        let mut remote_b = client.incoming.recv().await.unwrap();
+
+        let mut buffer = [0; 4];
+        a.write_all(b"PING").await.unwrap();
+        b.write_all(b"PONG").await.unwrap();
+        remote_b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"PONG", &buffer);
+        remote_a.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"PING", &buffer);
+    }
+
+    #[tokio::test]
+    async fn stream_exceeding_window_is_delivered() {
+        let (client, mut server) = connect();
+
+        let mut stream = client.open().await;
+        let mut remote = server.incoming.recv().await.unwrap();
+        let data: Vec<u8> = (0..WINDOW * 3).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let writer = tokio::spawn(async move {
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            stream
+        });
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(expected, received);
+
+        // the stream is half closed, the remote can still write back
+        let mut stream = writer.await.unwrap();
+        remote.write_all(b"DONE").await.unwrap();
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"DONE", &buffer);
+    }
+
+    #[tokio::test]
+    async fn dropping_handles_closes_streams() {
+        let (client, mut server) = connect();
+
+        let mut stream = client.open().await;
+        let _remote = server.incoming.recv().await.unwrap();
+        drop(server);
+
+        let mut buffer = [0; 4];
+        assert_eq!(0, stream.read(&mut buffer).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn close_command_closes_streams() {
+        let (client, mut server) = connect();
+
+        let mut stream = client.open().await;
+        let _remote = server.incoming.recv().await.unwrap();
+        client.commands.send(Command::Close).unwrap();
+
+        let mut buffer = [0; 4];
+        assert_eq!(0, stream.read(&mut buffer).await.unwrap());
+        assert!(server.incoming.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        let (client, mut server) = connect_with_timeout(Duration::from_millis(50));
+
+        // the connection stays open while a stream is open
+        let stream = client.open().await;
+        let remote = server.incoming.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!client.commands.is_closed());
+
+        // once the streams are closed the connection is closed after the timeout
+        drop(stream);
+        drop(remote);
+        let closed = timeout(Duration::from_millis(500), server.incoming.recv()).await;
+        assert!(matches!(closed, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn stream_exceeding_credit_is_reset() {
+        let (client, mut server) = connect_raw();
+        let (mut sink, mut source) = client.split();
+        sink.send(Stream::Open(1)).await.unwrap();
+        // the application never reads, so the credit isn't granted again once the buffer is full
+        let _remote = server.incoming.recv().await.unwrap();
+
+        tokio::spawn(async move {
+            let data = Bytes::from(vec![0; MAX_DATA_LEN]);
+            let frames = 2 * (WINDOW as usize + BUFFER) / MAX_DATA_LEN;
+            for _ in 0..frames {
+                if sink.send(Stream::Data(1, data.clone())).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let reset = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(frame)) = source.next().await {
+                if frame == Stream::Reset(1) {
+                    return true;
+                }
+            }
+            false
+        });
+        assert!(reset.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn remote_stream_with_local_id_is_ignored() {
+        let (mut client, mut server) = connect_raw();
+
+        // the server opens even streams
+        client.send(Stream::Open(2)).await.unwrap();
+        client.send(Stream::Open(1)).await.unwrap();
+        // the accepted stream is kept open so it doesn't send a close frame
+        let _accepted = server.incoming.recv().await.unwrap();
+        assert!(server.incoming.try_recv().is_err());
+
+        // the server's own stream 2 isn't taken over by the client
+        let mut stream = server.open().await;
+        assert_eq!(
+            Some(Stream::Open(2)),
+            client.next().await.map(Result::unwrap)
+        );
+        client
+            .send(Stream::Data(2, Bytes::from_static(b"PING")))
+            .await
+            .unwrap();
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"PING", &buffer);
+    }
+}
@@ -1,20 +1,124 @@
 use std::{sync::Arc, time::Duration};
 
 use futures::{SinkExt, StreamExt};
-use tokio::{net::TcpStream, time::timeout};
+use num_enum::TryFromPrimitive;
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
 use tokio_util::codec::Framed;
 use tracing::{debug, error};
 
 use crate::{
     err, hmac,
     manager::P2pManager,
-    peer::{Peer, PeerCandidate},
-    proto::{Connection, ConnectionCodec},
+    peer::{Peer, PeerCandidate, PeerId},
+    proto::{
+        Capabilities, Connection, ConnectionCodec, MessageType, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION, SIGNATURE,
+    },
+    tls,
 };
 
 const TIMEOUT_ERR: u32 = 2001;
 const NOT_FOUND_ERR: u32 = 2002;
 const AUTH_ERR: u32 = 2003;
+const VERSION_ERR: u32 = 2004;
+
+/// The length of the nonces each side challenges the other with
+const NONCE_LEN: usize = 32;
+
+/// Labels which keep either side's answer from being reflected back as the other's
+const CLIENT: &[u8] = b"fd-p2p connect client";
+const HOST: &[u8] = b"fd-p2p connect host";
+
+/// The challenges exchanged while connecting, each side proves it knows the pairing secret by
+/// returning an hmac over both nonces & ids. Fresh nonces mean a captured answer can't be replayed.
+/// The versions & capabilities are covered as well so they can't be downgraded.
+struct Challenge<'a> {
+    client_nonce: &'a [u8],
+    host_nonce: &'a [u8],
+    client: &'a PeerId,
+    host: &'a PeerId,
+    client_offer: (u16, Capabilities),
+    host_offer: (u16, Capabilities),
+}
+
+impl Challenge<'_> {
+    /// the data the given side signs to answer the challenge
+    fn data(&self, label: &[u8]) -> Vec<u8> {
+        [
+            label,
+            self.client_nonce,
+            self.host_nonce,
+            self.client.as_bytes(),
+            self.host.as_bytes(),
+            &self.client_offer.0.to_be_bytes(),
+            &self.client_offer.1.bits().to_be_bytes(),
+            &self.host_offer.0.to_be_bytes(),
+            &self.host_offer.1.bits().to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// pick the version & capabilities of the connection from the remote peer's offer. The peer with the newer version
+/// speaks the older one, as long as it is not older than [MIN_PROTOCOL_VERSION].
+fn negotiate(version: u16, caps: Capabilities) -> Result<(u16, Capabilities), err::ConnError> {
+    let caps = Capabilities::SUPPORTED.intersection(caps);
+    if version < MIN_PROTOCOL_VERSION || !caps.contains(Capabilities::REQUIRED) {
+        error!(
+            "peer speaks version {} with capabilities {:?}, we need at least version {} with {:?}",
+            version,
+            caps,
+            MIN_PROTOCOL_VERSION,
+            Capabilities::REQUIRED
+        );
+        return Err(err::ConnError::Incompatible);
+    }
+    Ok((version.min(PROTOCOL_VERSION), caps))
+}
+
+/// the error for a failure reported by the remote peer
+fn failure(code: u32) -> err::ConnError {
+    match code {
+        VERSION_ERR => err::ConnError::Incompatible,
+        code => err::ConnError::Failure(code),
+    }
+}
+
+fn generate_nonce() -> Vec<u8> {
+    rand::random::<[u8; NONCE_LEN]>().to_vec()
+}
+
+/// peek at the header of the first message to tell which handshake the remote peer started
+pub(crate) async fn peek_message_type(conn: &TcpStream) -> Result<MessageType, err::ConnError> {
+    let mut header = [0; 5];
+    let peek = async {
+        loop {
+            let n = conn
+                .peek(&mut header)
+                .await
+                .map_err(err::ParseError::from)?;
+            if n == 0 {
+                return Err(err::ConnError::Disconnect);
+            }
+            if n == header.len() {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let Ok(result) = timeout(Duration::from_secs(1), peek).await else {
+        error!("peer timed out before sending a message");
+        return Err(err::ConnError::Timeout);
+    };
+    result?;
+    if header[..2] != SIGNATURE {
+        return Err(err::ParseError::NotAPacket.into());
+    }
+    Ok(MessageType::try_from_primitive(header[4]).map_err(err::ParseError::from)?)
+}
 
 /// handshake as the client to attempt to connect as a connected peer
 pub(crate) async fn connect(
@@ -22,17 +126,16 @@ pub(crate) async fn connect(
     conn: TcpStream,
     peer: &PeerCandidate,
 ) -> Result<Peer, err::ConnError> {
-    // get auth code
-    let code = peer.auth.generate().unwrap();
-    let key = code.as_bytes();
-    let tag = hmac::sign(key, manager.id.as_bytes());
-
-    // send a connect request
+    // challenge the host with a fresh nonce
+    let key = peer.auth.secret();
+    let client_nonce = generate_nonce();
     let mut frame = Framed::new(conn, ConnectionCodec);
     frame
         .send(Connection::Request {
             id: manager.id.clone(),
-            tag: tag.as_ref().to_vec(),
+            nonce: client_nonce.clone(),
+            version: PROTOCOL_VERSION,
+            caps: Capabilities::SUPPORTED,
         })
         .await?;
 
@@ -49,17 +152,42 @@ pub(crate) async fn connect(
         }
         Some(res) => {
             match res? {
-                Connection::Response(tag) => {
-                    debug!("validating peer's totp code");
-                    if let Err(e) = hmac::verify(key, peer.id.as_bytes(), &tag) {
-                        error!("Error verifying totp hmac: {:?}", e);
+                Connection::Response {
+                    nonce,
+                    tag,
+                    version,
+                    caps,
+                } => {
+                    debug!("validating peer's response to the challenge");
+                    let challenge = Challenge {
+                        client_nonce: &client_nonce,
+                        host_nonce: &nonce,
+                        client: &manager.id,
+                        host: &peer.id,
+                        client_offer: (PROTOCOL_VERSION, Capabilities::SUPPORTED),
+                        host_offer: (version, caps),
+                    };
+                    if let Err(e) = hmac::verify(key, &challenge.data(HOST), &tag) {
+                        error!("Error verifying challenge hmac: {:?}", e);
                         _ = frame
                             .send(crate::proto::Connection::Failure(AUTH_ERR))
                             .await;
                         return Err(err::ConnError::Auth);
                     }
-                    // send a complete request & wait for a complete response
-                    frame.send(Connection::CompleteRequest).await?;
+                    let (version, caps) = match negotiate(version, caps) {
+                        Ok(negotiated) => negotiated,
+                        Err(e) => {
+                            _ = frame
+                                .send(crate::proto::Connection::Failure(VERSION_ERR))
+                                .await;
+                            return Err(e);
+                        }
+                    };
+                    // answer the host's challenge & wait for a complete response
+                    let tag = hmac::sign(key, &challenge.data(CLIENT));
+                    frame
+                        .send(Connection::CompleteRequest(tag.as_ref().to_vec()))
+                        .await?;
                     let Ok(complete) = timeout(Duration::from_secs(1), frame.next()).await else {
                         error!("peer timed out waiting for ConnectionCompleteResponse");
                         _ = frame.send(crate::proto::Connection::Failure(TIMEOUT_ERR)).await;
@@ -68,16 +196,25 @@ pub(crate) async fn connect(
                     match complete {
                         Some(res) => match res? {
                             Connection::CompleteResponse => {
+                                // upgrade to tls & verify the host's certificate belongs to the peer
+                                let conn =
+                                    tls::connect(manager, frame.into_inner(), &peer.id).await?;
                                 let connected = Peer::new(
                                     manager,
                                     crate::peer::ConnectionType::Client,
-                                    frame.into_inner(),
+                                    conn,
                                     peer.metadata.clone(),
+                                    version,
+                                    caps,
                                 )
                                 .unwrap();
                                 debug!("Peer is connected!");
                                 Ok(connected)
                             }
+                            Connection::Failure(code) => {
+                                error!("received error {} instead of ConnectionCompleteResponse", code);
+                                Err(failure(code))
+                            }
                             _ => {
                                 error!("peer recieved the wrong message instead of ConnectionCompleteResponse");
                                 Err(err::ConnError::Msg)
@@ -91,7 +228,7 @@ pub(crate) async fn connect(
                 }
                 Connection::Failure(code) => {
                     error!("received error {} instead of ConnectionResponse", code);
-                    Err(err::ConnError::Failure(code))
+                    Err(failure(code))
                 }
                 _ => {
                     error!("peer recieved the wrong message instead of ConnectionResponse");
@@ -123,26 +260,47 @@ pub(crate) async fn accept(
         }
         Some(req) => {
             match req? {
-                Connection::Request { id, tag } => {
+                Connection::Request {
+                    id,
+                    nonce,
+                    version,
+                    caps,
+                } => {
                     let Some(peer) = manager.get_peer_candidate(&id) else {
                         _ = frame.send(crate::proto::Connection::Failure(NOT_FOUND_ERR)).await;
                         error!("peer is not known nor discovered");
                         return Err(err::ConnError::NotFound);
                     };
-                    debug!("validating peer's totp code");
-                    let code = peer.auth.generate().unwrap();
-                    let key = code.as_bytes();
-                    if let Err(e) = hmac::verify(key, peer.id.as_bytes(), &tag) {
-                        error!("Error verifying totp hmac: {:?}", e);
-                        _ = frame
-                            .send(crate::proto::Connection::Failure(AUTH_ERR))
-                            .await;
-                        return Err(err::ConnError::Auth);
-                    }
-                    let tag = hmac::sign(key, manager.id.as_bytes());
+                    let client_offer = (version, caps);
+                    let (version, caps) = match negotiate(version, caps) {
+                        Ok(negotiated) => negotiated,
+                        Err(e) => {
+                            _ = frame
+                                .send(crate::proto::Connection::Failure(VERSION_ERR))
+                                .await;
+                            return Err(e);
+                        }
+                    };
+                    // answer the client's challenge with our own nonce, which the client has to answer in turn
+                    let key = peer.auth.secret();
+                    let host_nonce = generate_nonce();
+                    let challenge = Challenge {
+                        client_nonce: &nonce,
+                        host_nonce: &host_nonce,
+                        client: &peer.id,
+                        host: &manager.id,
+                        client_offer,
+                        host_offer: (PROTOCOL_VERSION, Capabilities::SUPPORTED),
+                    };
+                    let tag = hmac::sign(key, &challenge.data(HOST));
                     // send a connect response & wait for a complete request
                     frame
-                        .send(crate::proto::Connection::Response(tag.as_ref().to_vec()))
+                        .send(crate::proto::Connection::Response {
+                            nonce: host_nonce.clone(),
+                            tag: tag.as_ref().to_vec(),
+                            version: PROTOCOL_VERSION,
+                            caps: Capabilities::SUPPORTED,
+                        })
                         .await?;
                     let Ok(complete) = timeout(Duration::from_secs(1), frame.next()).await else {
                         error!("peer timed out waiting for ConnectionCompleteRequest");
@@ -152,19 +310,40 @@ pub(crate) async fn accept(
                     match complete {
                         Some(res) => {
                             match res? {
-                                Connection::CompleteRequest => {
+                                Connection::CompleteRequest(tag) => {
+                                    debug!("validating peer's response to the challenge");
+                                    if let Err(e) = hmac::verify(key, &challenge.data(CLIENT), &tag)
+                                    {
+                                        error!("Error verifying challenge hmac: {:?}", e);
+                                        _ = frame
+                                            .send(crate::proto::Connection::Failure(AUTH_ERR))
+                                            .await;
+                                        return Err(err::ConnError::Auth);
+                                    }
                                     // send a complete response
                                     frame.send(Connection::CompleteResponse).await?;
+                                    // upgrade to tls & verify the client's certificate belongs to the peer
+                                    let conn =
+                                        tls::accept(manager, frame.into_inner(), &peer.id).await?;
                                     let connected = Peer::new(
                                         manager,
                                         crate::peer::ConnectionType::Server,
-                                        frame.into_inner(),
+                                        conn,
                                         peer.metadata,
+                                        version,
+                                        caps,
                                     )
                                     .unwrap();
                                     debug!("Peer is connected!");
                                     Ok(connected)
                                 }
+                                Connection::Failure(code) => {
+                                    error!(
+                                        "received error {} instead of ConnectionCompleteRequest",
+                                        code
+                                    );
+                                    Err(failure(code))
+                                }
                                 _ => {
                                     error!("peer recieved the wrong message instead of ConnectionCompleteRequest");
                                     Err(err::ConnError::Msg)
@@ -179,7 +358,7 @@ pub(crate) async fn accept(
                 }
                 Connection::Failure(code) => {
                     error!("received error {} instead of ConnectionRequest", code);
-                    Err(err::ConnError::Failure(code))
+                    Err(failure(code))
                 }
                 _ => {
                     error!("peer recieved the wrong message instead of ConnectionRequest");
@@ -0,0 +1,206 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+use tracing::{debug, error};
+
+use crate::{
+    err,
+    manager::P2pManager,
+    pairing::{self, Decision, PairingRequest},
+    peer::PeerMetadata,
+    proto::{Pairing, PairingCodec},
+    spake::{Role, Spake2},
+    tls,
+};
+
+const TIMEOUT_ERR: u32 = 3001;
+const AUTH_ERR: u32 = 3002;
+
+/// How long the user has to accept or reject a pairing request
+const DECISION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// handshake as the client to request pairing with a nearby peer, returning the shared secret once accepted.
+/// With a code the host's user has to enter the same code, which is never sent over the network.
+pub(crate) async fn request(
+    manager: &Arc<P2pManager>,
+    conn: TcpStream,
+    peer: &PeerMetadata,
+    code: Option<&str>,
+) -> Result<String, err::ConnError> {
+    // identify ourselves so the host knows which certificate to expect
+    let mut frame = Framed::new(conn, PairingCodec);
+    frame
+        .send(Pairing::Request {
+            metadata: manager.metadata.clone(),
+            code: code.is_some(),
+        })
+        .await?;
+
+    // wait for the host to be ready
+    match receive(&mut frame, Duration::from_secs(1)).await? {
+        Pairing::Ready => {}
+        _ => {
+            error!("peer recieved the wrong message instead of ready");
+            return Err(err::ConnError::Msg);
+        }
+    }
+
+    // upgrade to tls & verify the host's certificate belongs to the nearby peer
+    let conn = tls::connect(manager, frame.into_inner(), &peer.id).await?;
+    let mut frame = Framed::new(conn, PairingCodec);
+
+    let spake = code.map(|code| Spake2::start(Role::Client, code));
+    if let Some(spake) = &spake {
+        frame.send(Pairing::Spake(spake.message().to_vec())).await?;
+    }
+
+    // wait for the remote user to decide, allowing some extra time for the response to arrive
+    let wait = DECISION_TIMEOUT + Duration::from_secs(5);
+    match (receive(&mut frame, wait).await?, spake) {
+        (Pairing::Accept(secret), None) => {
+            debug!("peer accepted the pairing request");
+            Ok(secret)
+        }
+        (Pairing::Spake(message), Some(spake)) => {
+            debug!("peer accepted the pairing request with a code");
+            let key = spake.finish(&message, &manager.metadata.id, &peer.id)?;
+
+            // the host proves it entered the same code first, then we do
+            let Pairing::Confirm(tag) = receive(&mut frame, Duration::from_secs(1)).await? else {
+                error!("peer recieved the wrong message instead of a confirmation");
+                return Err(err::ConnError::Msg);
+            };
+            if key.verify(Role::Host, &tag).is_err() {
+                error!("peer entered the wrong pairing code");
+                _ = frame.send(Pairing::Failure(AUTH_ERR)).await;
+                return Err(err::ConnError::Auth);
+            }
+            frame
+                .send(Pairing::Confirm(key.confirmation(Role::Client)))
+                .await?;
+            _ = frame.close().await;
+            Ok(key.secret())
+        }
+        (Pairing::Reject, _) => {
+            debug!("peer rejected the pairing request");
+            Err(err::ConnError::Rejected)
+        }
+        _ => {
+            error!("peer recieved the wrong message instead of a pairing response");
+            Err(err::ConnError::Msg)
+        }
+    }
+}
+
+/// handshake as the host of a pairing request, the application decides whether to pair with the peer
+pub(crate) async fn accept(
+    manager: &Arc<P2pManager>,
+    conn: TcpStream,
+) -> Result<(PeerMetadata, String), err::ConnError> {
+    let mut frame = Framed::new(conn, PairingCodec);
+
+    // wait for a pairing request
+    let (peer, code) = match receive(&mut frame, Duration::from_secs(1)).await? {
+        Pairing::Request { metadata, code } => (metadata, code),
+        _ => {
+            error!("peer recieved the wrong message instead of a pairing request");
+            return Err(err::ConnError::Msg);
+        }
+    };
+
+    // upgrade to tls & verify the client's certificate belongs to the peer it claims to be
+    frame.send(Pairing::Ready).await?;
+    let conn = tls::accept(manager, frame.into_inner(), &peer.id).await?;
+    let mut frame = Framed::new(conn, PairingCodec);
+
+    // the client's half of the exchange is sent straight away when pairing with a code
+    let message = if code {
+        let Pairing::Spake(message) = receive(&mut frame, Duration::from_secs(1)).await? else {
+            error!("peer recieved the wrong message instead of a pairing code exchange");
+            return Err(err::ConnError::Msg);
+        };
+        Some(message)
+    } else {
+        None
+    };
+
+    // ask the application, no answer in time or an answer of the wrong kind is a rejection
+    let (request, decision) = PairingRequest::new(peer.clone(), code);
+    manager.handle_pairing_request(request);
+    let decision = timeout(DECISION_TIMEOUT, decision).await;
+    let secret = match (decision, message) {
+        (Ok(Ok(Decision::Accept)), None) => {
+            let secret = pairing::generate_secret();
+            frame.send(Pairing::Accept(secret.clone())).await?;
+            secret
+        }
+        (Ok(Ok(Decision::Code(code))), Some(message)) => {
+            let spake = Spake2::start(Role::Host, &code);
+            frame.send(Pairing::Spake(spake.message().to_vec())).await?;
+            let key = spake.finish(&message, &peer.id, &manager.metadata.id)?;
+            frame
+                .send(Pairing::Confirm(key.confirmation(Role::Host)))
+                .await?;
+
+            // the client only confirms if we entered the code it shows
+            let Pairing::Confirm(tag) = receive(&mut frame, Duration::from_secs(1)).await? else {
+                error!("peer recieved the wrong message instead of a confirmation");
+                return Err(err::ConnError::Msg);
+            };
+            if key.verify(Role::Client, &tag).is_err() {
+                error!("peer did not derive the same pairing key");
+                _ = frame.send(Pairing::Failure(AUTH_ERR)).await;
+                return Err(err::ConnError::Auth);
+            }
+            key.secret()
+        }
+        _ => {
+            debug!("pairing request from {} was rejected", peer.id);
+            _ = frame.send(Pairing::Reject).await;
+            _ = frame.close().await;
+            return Err(err::ConnError::Rejected);
+        }
+    };
+
+    _ = frame.close().await;
+    debug!("paired with {}", peer.id);
+    Ok((peer, secret))
+}
+
+/// wait for the next pairing message, a failure reported by the peer is returned as an error
+async fn receive<T>(
+    frame: &mut Framed<T, PairingCodec>,
+    wait: Duration,
+) -> Result<Pairing, err::ConnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let Ok(message) = timeout(wait, frame.next()).await else {
+        error!("peer timed out waiting for a pairing message");
+        _ = frame.send(Pairing::Failure(TIMEOUT_ERR)).await;
+        return Err(err::ConnError::Timeout);
+    };
+    match message {
+        None => {
+            error!("peer closed the connection");
+            Err(err::ConnError::Disconnect)
+        }
+        Some(res) => match res? {
+            Pairing::Failure(AUTH_ERR) => {
+                error!("peer could not verify the pairing code");
+                Err(err::ConnError::Auth)
+            }
+            Pairing::Failure(code) => {
+                error!("received error {} during pairing", code);
+                Err(err::ConnError::Failure(code))
+            }
+            message => Ok(message),
+        },
+    }
+}
@@ -1,8 +1,10 @@
 use std::str::FromStr;
 
+use rand::Rng;
+use tokio::sync::oneshot;
 use totp_rs::{Secret, TOTP};
 
-use crate::err;
+use crate::{err, peer::PeerMetadata};
 
 pub struct Png(String);
 
@@ -44,6 +46,67 @@ impl PairingAuthenticator {
     pub fn generate(&self) -> Result<String, err::PairingError> {
         Ok(self.totp.generate_current()?)
     }
+
+    /// the long-term secret shared with the paired peer, which keys the connection handshake
+    pub(crate) fn secret(&self) -> &[u8] {
+        &self.totp.secret
+    }
+}
+
+/// generate a new random secret to share with a peer when pairing
+pub(crate) fn generate_secret() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// generate a new 6 digit code for the user to enter on the peer they are pairing with
+pub fn generate_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// The application's answer to a [PairingRequest]
+#[derive(Debug, PartialEq)]
+pub(crate) enum Decision {
+    Reject,
+    Accept,
+    Code(String),
+}
+
+/// A request from a nearby peer to pair, which the application either accepts or rejects.
+/// Dropping the request rejects it.
+#[derive(Debug)]
+pub struct PairingRequest {
+    /// the metadata of the peer requesting to pair
+    pub peer: PeerMetadata,
+
+    /// whether the requesting peer shows a code which the user has to enter to accept
+    pub code: bool,
+
+    reply: oneshot::Sender<Decision>,
+}
+
+impl PairingRequest {
+    pub(crate) fn new(peer: PeerMetadata, code: bool) -> (Self, oneshot::Receiver<Decision>) {
+        let (reply, rx) = oneshot::channel();
+        (Self { peer, code, reply }, rx)
+    }
+
+    /// pair with the peer, a request which requires a code is rejected
+    pub fn accept(self) {
+        _ = self.reply.send(Decision::Accept);
+    }
+
+    /// pair with the peer using the code the user entered, pairing fails if it does not match the peer's code
+    pub fn accept_with_code(self, code: String) {
+        _ = self.reply.send(Decision::Code(code));
+    }
+
+    /// refuse to pair with the peer
+    pub fn reject(self) {
+        _ = self.reply.send(Decision::Reject);
+    }
 }
 
 impl ToString for PairingAuthenticator {
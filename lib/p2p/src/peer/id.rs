@@ -24,8 +24,13 @@ impl PeerId {
 
     /// from_cert will derive a [PeerId] from a [rustls::Certificate].
     pub fn from_cert(cert: &rustls::Certificate) -> Self {
+        Self::from_der(&cert.0)
+    }
+
+    /// from_der will derive a [PeerId] from a DER encoded certificate.
+    pub fn from_der(cert: &[u8]) -> Self {
         // SHA-1 is used due to the limitation of the length of a DNS record used for mDNS local network discovery.
-        let peer_id: String = digest(&ring::digest::SHA256, &cert.0)
+        let peer_id: String = digest(&ring::digest::SHA256, cert)
             .as_ref()
             .iter()
             .map(|b| format!("{b:02x}"))
@@ -4,10 +4,16 @@ use std::{
     hash::Hash,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
+    time::Duration,
 };
-use tokio::{io::DuplexStream, net::TcpStream};
+use tokio::{
+    io::DuplexStream,
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex},
+};
+use tokio_rustls::TlsStream;
 
-use crate::{manager::P2pManager, pairing::PairingAuthenticator};
+use crate::{err, manager::P2pManager, mux, pairing::PairingAuthenticator, proto::Capabilities};
 
 use super::PeerId;
 
@@ -60,6 +66,7 @@ impl Hash for PeerMetadata {
 )]
 #[repr(u16)]
 pub enum DeviceType {
+    /// also any device type this version doesn't know about
     #[default]
     Unknown = 0,
     // XboxOne = 1,
@@ -73,6 +80,7 @@ pub enum DeviceType {
     // SurfaceHub = 14,
     WindowsLaptop = 15,
     // WindowsTablet = 16
+    AppleMac = 17,
 }
 
 // #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,7 +95,7 @@ pub enum DeviceType {
 /// A peer candidate discovered through multicast may have been modified by an attacker on your local network but this is
 /// deemed acceptable as the attacker can only modify primitive metadata such a name or device type.
 /// When we initiated communication with the device we will ensure we are talking to the correct device using
-/// TOTP not PAKE (specially SPAKE2) for pairing and verifying the TLS (soon) certificate for general communication.
+/// TOTP not PAKE (specially SPAKE2) for pairing and verifying the TLS certificate for general communication.
 #[derive(Debug, Clone /*Serialize, Deserialize*/)] // TODO: Type
 pub struct PeerCandidate {
     pub id: PeerId,
@@ -124,7 +132,8 @@ pub enum ConnectionType {
 /// It also stores a reference to the network manager for communication back to the [P2PManager].
 /// The [Peer] acts as an abstraction above the QUIC (soon) connection which could be a client
 /// or server so that when building code we don't have to think about the technicalities of the connection.
-#[derive(Debug)]
+/// Cloning a [Peer] creates another handle to the same connection.
+#[derive(Debug, Clone)]
 pub struct Peer {
     /// peer_id holds the id of the remote peer. This is their unique identifier.
     pub id: PeerId,
@@ -135,70 +144,94 @@ pub struct Peer {
     /// metadata holds the metadata of the remote peer. This includes information such as their display name and version.
     pub metadata: PeerMetadata,
 
-    /// conn holds the connection that is being used to communicate with the remote peer. This allows creating new streams.
-    pub conn: DuplexStream,
+    /// version holds the protocol version negotiated with the remote peer.
+    pub version: u16,
+
+    /// caps holds the protocol features both peers support, higher layers should only use these features.
+    pub caps: Capabilities,
+
+    /// conn holds the channel to the task multiplexing streams over the connection with the remote peer.
+    conn: mpsc::UnboundedSender<mux::Command>,
+
+    /// incoming holds the streams opened by the remote peer which are waiting to be accepted.
+    incoming: Arc<Mutex<mpsc::UnboundedReceiver<DuplexStream>>>,
     // manager is a reference to the p2p manager. This is used to ensure the state of managed connections is updated when Peer is dropped
     // manager: Arc<P2pManager>,
 }
 
 impl Peer {
     /// create a new peer from a network connection.
-    /// Peers can only be created after mutual validation of pairing codes
+    /// Peers can only be created after mutual validation of pairing codes and certificates
     pub(crate) fn new(
         manager: &Arc<P2pManager>,
         conn_type: ConnectionType,
-        conn: TcpStream,
+        conn: TlsStream<TcpStream>,
         metadata: PeerMetadata,
+        version: u16,
+        caps: Capabilities,
     ) -> Result<Self, ()> {
-        let (transport, application) = tokio::io::duplex(64);
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
 
         let id = metadata.id.clone();
         let m = manager.clone();
-        tokio::spawn(handler(conn, application, m, id.clone()));
+        tokio::spawn(handler(
+            conn,
+            conn_type.clone(),
+            manager.idle_timeout,
+            commands_rx,
+            incoming_tx,
+            m,
+            id.clone(),
+        ));
 
         Ok(Self {
             id,
             conn_type,
             metadata,
-            conn: transport,
+            version,
+            caps,
+            conn: commands,
+            incoming: Arc::new(Mutex::new(incoming)),
         })
     }
-}
 
-/// continuously running handler for transporting data between local peer & remote peer
-async fn handler(conn: TcpStream, app: DuplexStream, manager: Arc<P2pManager>, id: PeerId) {
-    let (mut transport_reader, mut transport_writer) = tokio::io::split(conn);
-    let (mut app_reader, mut app_writer) = tokio::io::split(app);
-
-    loop {
-        tokio::select! {
-            result = tokio::io::copy(&mut transport_reader, &mut app_writer) => {
-                match result {
-                    Ok(0) => {
-                        tracing::debug!("transport buffer drained");
-                        break;
-                    }
-                    Err(e) => {
-                        tracing::error!("error occured writing data to application {:?}", e);
-                        break;
-                    }
-                    _ => {}
-                }
-            },
-            result = tokio::io::copy(&mut app_reader, &mut transport_writer) => {
-                match result {
-                    Ok(0) => {
-                        tracing::debug!("application buffer drained");
-                        break;
-                    }
-                    Err(e) => {
-                        tracing::error!("error occured writing data to transport {:?}", e);
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-        }
+    /// open a new stream to the remote peer. Each stream has its own flow control and can be closed independently
+    /// of the other streams, shutting down the stream tells the remote peer we are done writing.
+    pub async fn open_stream(&self) -> Result<DuplexStream, err::ConnError> {
+        let (tx, rx) = oneshot::channel();
+        self.conn
+            .send(mux::Command::Open(tx))
+            .map_err(|_| err::ConnError::Disconnect)?;
+        rx.await.map_err(|_| err::ConnError::Disconnect)
+    }
+
+    /// wait for the remote peer to open a new stream. Returns [None] once the connection is closed.
+    pub async fn accept_stream(&self) -> Option<DuplexStream> {
+        self.incoming.lock().await.recv().await
+    }
+
+    /// close the connection to the remote peer along with all of its streams, including those of other handles.
+    pub fn close(&self) {
+        _ = self.conn.send(mux::Command::Close);
     }
+
+    /// whether the connection to the remote peer has closed
+    pub fn is_closed(&self) -> bool {
+        self.conn.is_closed()
+    }
+}
+
+/// continuously running handler for multiplexing streams between local peer & remote peer
+async fn handler(
+    conn: TlsStream<TcpStream>,
+    conn_type: ConnectionType,
+    idle_timeout: Duration,
+    commands: mpsc::UnboundedReceiver<mux::Command>,
+    incoming: mpsc::UnboundedSender<DuplexStream>,
+    manager: Arc<P2pManager>,
+    id: PeerId,
+) {
+    mux::run(conn, conn_type, idle_timeout, commands, incoming).await;
     manager.peer_disconnected(&id);
 }
@@ -1,7 +1,7 @@
 use std::net::SocketAddr;
 
 use byteorder::{BigEndian, ReadBytesExt};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -48,8 +48,8 @@ pub enum MessageType {
     // None = 0,
     Discovery = 1,
     Connect = 2,
-    // Control = 3,
-    // Session = 4,
+    Stream = 3,
+    Pair = 4,
     // Ack = 5
 }
 
@@ -74,28 +74,8 @@ impl Decoder for DiscoveryCodec {
                 Ok(Some(event::DiscoveryEvent::PresenceRequest(dedup)))
             }
             1 => {
-                let device_type_raw = src.get_u16();
-                let device_type = DeviceType::try_from_primitive(device_type_raw)?;
-                let device_name_length = src.get_u16();
-                let device_name_bytes = src.split_to(device_name_length.into());
-                let device_name_raw = &device_name_bytes[..];
-                let device_name = String::from_utf8(device_name_raw.to_vec()).unwrap();
-                let device_id_raw = src.split_to(40);
-                let device_id = String::from_utf8(device_id_raw.to_vec()).unwrap();
-                let id = PeerId::from_string(device_id)?;
-                let device_addr_length = src.get_u16();
-                let device_addr_bytes = src.split_to(device_addr_length.into());
-                let device_addr_str = String::from_utf8(device_addr_bytes.to_vec()).unwrap();
-                let device_addr: SocketAddr = device_addr_str.parse()?;
-
-                Ok(Some(event::DiscoveryEvent::PresenceResponse(
-                    PeerMetadata {
-                        typ: device_type,
-                        name: device_name,
-                        id,
-                        addr: device_addr,
-                    },
-                )))
+                let metadata = get_metadata(src)?;
+                Ok(Some(event::DiscoveryEvent::PresenceResponse(metadata)))
             }
             x => Err(Self::Error::Enum(x.into())),
         }
@@ -117,38 +97,132 @@ impl Encoder<event::DiscoveryEvent> for DiscoveryCodec {
                 dst.put_u32(dedup); // DedupId
             }
             event::DiscoveryEvent::PresenceResponse(metadata) => {
-                let name = metadata.name.as_bytes();
                 dst.put_u8(1); // DiscoveryType
-                dst.put_u16(metadata.typ.into()); // DeviceType
-                dst.put_u16(name.len().try_into().unwrap()); // DeviceNameLength
-                dst.put(name); // DeviceName
-                dst.put(metadata.id.as_bytes()); // DeviceId
-                let addr_str = metadata.addr.to_string();
-                let addr = addr_str.as_bytes();
-                dst.put_u16(addr.len().try_into().unwrap()); // DeviceAddressLength
-                dst.put(addr); // DeviceAddress
+                put_metadata(&metadata, dst);
             }
         }
         Ok(())
     }
 }
 
+/// The encoded length of a peer's metadata
+pub(crate) fn metadata_len(metadata: &PeerMetadata) -> u16 {
+    2 + 2
+        + u16::try_from(metadata.name.len()).unwrap()
+        + 40
+        + 2
+        + u16::try_from(metadata.addr.to_string().len()).unwrap()
+}
+
+fn put_metadata(metadata: &PeerMetadata, dst: &mut BytesMut) {
+    let name = metadata.name.as_bytes();
+    dst.put_u16(metadata.typ.into()); // DeviceType
+    dst.put_u16(name.len().try_into().unwrap()); // DeviceNameLength
+    dst.put(name); // DeviceName
+    dst.put(metadata.id.as_bytes()); // DeviceId
+    let addr_str = metadata.addr.to_string();
+    let addr = addr_str.as_bytes();
+    dst.put_u16(addr.len().try_into().unwrap()); // DeviceAddressLength
+    dst.put(addr); // DeviceAddress
+}
+
+fn get_metadata(src: &mut BytesMut) -> Result<PeerMetadata, err::ParseError> {
+    ensure(src, 2 + 2)?;
+    let device_type_raw = src.get_u16();
+    let device_type = DeviceType::try_from_primitive(device_type_raw)?;
+    let device_name_length = src.get_u16();
+    ensure(src, usize::from(device_name_length) + 40 + 2)?;
+    let device_name_bytes = src.split_to(device_name_length.into());
+    let device_name_raw = &device_name_bytes[..];
+    let device_name = String::from_utf8(device_name_raw.to_vec())?;
+    let device_id_raw = src.split_to(40);
+    let device_id = String::from_utf8(device_id_raw.to_vec())?;
+    let id = PeerId::from_string(device_id)?;
+    let device_addr_length = src.get_u16();
+    ensure(src, device_addr_length.into())?;
+    let device_addr_bytes = src.split_to(device_addr_length.into());
+    let device_addr_str = String::from_utf8(device_addr_bytes.to_vec())?;
+    let device_addr: SocketAddr = device_addr_str.parse()?;
+
+    Ok(PeerMetadata {
+        typ: device_type,
+        name: device_name,
+        id,
+        addr: device_addr,
+    })
+}
+
+/// The version of the protocol spoken by this peer
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest version of the protocol this peer can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// A set of optional protocol features, each peer offers the features it supports while connecting and
+/// the connection uses the features both peers support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// the connection is upgraded to tls after authenticating
+    pub const TLS: Capabilities = Capabilities(1 << 0);
+    /// streams are multiplexed over the connection
+    pub const MUX: Capabilities = Capabilities(1 << 1);
+    /// stream data can be compressed
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+
+    /// the features this peer supports
+    pub const SUPPORTED: Capabilities = Capabilities(Self::TLS.0 | Self::MUX.0);
+    /// the features a connection can't be established without
+    pub const REQUIRED: Capabilities = Capabilities(Self::TLS.0 | Self::MUX.0);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// whether all of the given features are in the set
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the features in both sets
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Self(self.0 & other.0)
+    }
+}
+
 pub struct ConnectionCodec;
 
 pub enum Connection {
-    Request { id: PeerId, tag: Vec<u8> }, // sent by client
-    Response(Vec<u8>),                    // sent by host
-    CompleteRequest,                      // sent by client
-    CompleteResponse,                     // sent by host
-    Failure(u32),                         // sent by either on error
+    // sent by client
+    Request {
+        id: PeerId,
+        nonce: Vec<u8>,
+        version: u16,
+        caps: Capabilities,
+    },
+    // sent by host
+    Response {
+        nonce: Vec<u8>,
+        tag: Vec<u8>,
+        version: u16,
+        caps: Capabilities,
+    },
+    CompleteRequest(Vec<u8>), // sent by client
+    CompleteResponse,         // sent by host
+    Failure(u32),             // sent by either on error
 }
 
 impl Frame for Connection {
     fn len(&self) -> u16 {
         match self {
-            Connection::Request { .. } => 1 + 40 + 32,
-            Connection::Response(_) => 1 + 32,
-            Connection::CompleteRequest => 1,
+            Connection::Request { .. } => 1 + 40 + 32 + 2 + 4,
+            Connection::Response { .. } => 1 + 32 + 32 + 2 + 4,
+            Connection::CompleteRequest(_) => 1 + 32,
             Connection::CompleteResponse => 1,
             Connection::Failure(_) => 1 + 4,
         }
@@ -169,24 +243,48 @@ impl Decoder for ConnectionCodec {
             return Err(Self::Error::MsgType(header.message_type));
         }
 
-        match src.get_u8() {
+        // the peer isn't authenticated yet, every field is checked against what is left of the frame
+        let mut body = split_body(&header, src)?;
+        ensure(&body, 1)?;
+        match body.get_u8() {
             0 => {
-                let peer_id_raw = src.split_to(40);
-                let peer_id =
-                    PeerId::from_string(String::from_utf8(peer_id_raw.to_vec()).unwrap()).unwrap();
-                let hmac = src.split_to(32).to_vec();
+                ensure(&body, 40 + 32 + 2 + 4)?;
+                let peer_id_raw = body.split_to(40);
+                let peer_id = PeerId::from_string(String::from_utf8(peer_id_raw.to_vec())?)?;
+                let nonce = body.split_to(32).to_vec();
+                let version = body.get_u16();
+                let caps = Capabilities::from_bits(body.get_u32());
                 Ok(Some(Connection::Request {
                     id: peer_id,
-                    tag: hmac,
+                    nonce,
+                    version,
+                    caps,
                 }))
             }
             1 => {
-                let hmac = src.split_to(32).to_vec();
-                Ok(Some(Connection::Response(hmac)))
+                ensure(&body, 32 + 32 + 2 + 4)?;
+                let nonce = body.split_to(32).to_vec();
+                let tag = body.split_to(32).to_vec();
+                let version = body.get_u16();
+                let caps = Capabilities::from_bits(body.get_u32());
+                Ok(Some(Connection::Response {
+                    nonce,
+                    tag,
+                    version,
+                    caps,
+                }))
+            }
+            2 => {
+                ensure(&body, 32)?;
+                Ok(Some(Connection::CompleteRequest(
+                    body.split_to(32).to_vec(),
+                )))
             }
-            2 => Ok(Some(Connection::CompleteRequest)),
             3 => Ok(Some(Connection::CompleteResponse)),
-            4 => Ok(Some(Connection::Failure(src.get_u32()))),
+            4 => {
+                ensure(&body, 4)?;
+                Ok(Some(Connection::Failure(body.get_u32())))
+            }
             x => Err(Self::Error::Enum(x.into())),
         }
     }
@@ -198,17 +296,33 @@ impl Encoder<Connection> for ConnectionCodec {
     fn encode(&mut self, item: Connection, dst: &mut BytesMut) -> Result<(), Self::Error> {
         HeaderCodec.encode(Header::new(MessageType::Connect, &item), dst)?;
         match item {
-            Connection::Request { id, tag } => {
+            Connection::Request {
+                id,
+                nonce,
+                version,
+                caps,
+            } => {
                 dst.put_u8(0);
                 dst.put(id.as_bytes());
-                dst.put(tag.as_ref());
+                dst.put(nonce.as_ref());
+                dst.put_u16(version);
+                dst.put_u32(caps.bits());
             }
-            Connection::Response(tag) => {
+            Connection::Response {
+                nonce,
+                tag,
+                version,
+                caps,
+            } => {
                 dst.put_u8(1);
+                dst.put(nonce.as_ref());
                 dst.put(tag.as_ref());
+                dst.put_u16(version);
+                dst.put_u32(caps.bits());
             }
-            Connection::CompleteRequest => {
+            Connection::CompleteRequest(tag) => {
                 dst.put_u8(2);
+                dst.put(tag.as_ref());
             }
             Connection::CompleteResponse => {
                 dst.put_u8(3);
@@ -222,6 +336,239 @@ impl Encoder<Connection> for ConnectionCodec {
     }
 }
 
+pub struct StreamCodec;
+
+/// The largest amount of data a single [Stream::Data] frame may carry
+pub(crate) const MAX_DATA_LEN: usize = 16 * 1024;
+
+/// Frames which multiplex many logical streams over a single connection
+#[derive(Debug, PartialEq)]
+pub enum Stream {
+    Open(u32),        // sent by either to open a new stream
+    Data(u32, Bytes), // sent by either to write to a stream
+    Window(u32, u32), // sent by the reader to allow the writer to send more data
+    Close(u32),       // sent by either when it is done writing to a stream
+    Reset(u32),       // sent by either to abort a stream
+}
+
+impl Frame for Stream {
+    fn len(&self) -> u16 {
+        match self {
+            Stream::Open(_) => 1 + 4,
+            Stream::Data(_, data) => 1 + 4 + u16::try_from(data.len()).unwrap(),
+            Stream::Window(..) => 1 + 4 + 4,
+            Stream::Close(_) => 1 + 4,
+            Stream::Reset(_) => 1 + 4,
+        }
+    }
+}
+
+impl Decoder for StreamCodec {
+    type Item = Stream;
+
+    type Error = err::ParseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(header) = HeaderCodec.decode(src)? else {
+            return Ok(None);
+        };
+
+        if header.message_type != MessageType::Stream {
+            return Err(Self::Error::MsgType(header.message_type));
+        }
+
+        let mut body = split_body(&header, src)?;
+        ensure(&body, 1 + 4)?;
+        let stream_type = body.get_u8();
+        let id = body.get_u32();
+        match stream_type {
+            0 => Ok(Some(Stream::Open(id))),
+            1 => Ok(Some(Stream::Data(id, body.freeze()))),
+            2 => {
+                ensure(&body, 4)?;
+                Ok(Some(Stream::Window(id, body.get_u32())))
+            }
+            3 => Ok(Some(Stream::Close(id))),
+            4 => Ok(Some(Stream::Reset(id))),
+            x => Err(Self::Error::Enum(x.into())),
+        }
+    }
+}
+
+impl Encoder<Stream> for StreamCodec {
+    type Error = err::ParseError;
+
+    fn encode(&mut self, item: Stream, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        HeaderCodec.encode(Header::new(MessageType::Stream, &item), dst)?;
+        match item {
+            Stream::Open(id) => {
+                dst.put_u8(0);
+                dst.put_u32(id);
+            }
+            Stream::Data(id, data) => {
+                dst.put_u8(1);
+                dst.put_u32(id);
+                dst.put(data);
+            }
+            Stream::Window(id, credit) => {
+                dst.put_u8(2);
+                dst.put_u32(id);
+                dst.put_u32(credit);
+            }
+            Stream::Close(id) => {
+                dst.put_u8(3);
+                dst.put_u32(id);
+            }
+            Stream::Reset(id) => {
+                dst.put_u8(4);
+                dst.put_u32(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PairingCodec;
+
+/// The length of the SPAKE2 messages & key confirmations exchanged when pairing with a code
+const PAIRING_KEY_LEN: usize = 32;
+
+/// Messages which pair a peer with a nearby peer
+#[derive(Debug, PartialEq)]
+pub enum Pairing {
+    Request { metadata: PeerMetadata, code: bool }, // sent by client
+    Ready,            // sent by host, both peers upgrade to tls after it
+    Accept(String),   // sent by host with the shared secret once the user accepted
+    Reject,           // sent by host once the user rejected
+    Failure(u32),     // sent by either on error
+    Spake(Vec<u8>),   // sent by both when pairing with a code, the SPAKE2 message
+    Confirm(Vec<u8>), // sent by both when pairing with a code, proves the same key was derived
+}
+
+impl Frame for Pairing {
+    fn len(&self) -> u16 {
+        match self {
+            Pairing::Request { metadata, .. } => 1 + 1 + metadata_len(metadata),
+            Pairing::Ready => 1,
+            Pairing::Accept(secret) => 1 + 2 + u16::try_from(secret.len()).unwrap(),
+            Pairing::Reject => 1,
+            Pairing::Failure(_) => 1 + 4,
+            Pairing::Spake(_) => 1 + PAIRING_KEY_LEN as u16,
+            Pairing::Confirm(_) => 1 + PAIRING_KEY_LEN as u16,
+        }
+    }
+}
+
+impl Decoder for PairingCodec {
+    type Item = Pairing;
+
+    type Error = err::ParseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(header) = HeaderCodec.decode(src)? else {
+            return Ok(None);
+        };
+
+        if header.message_type != MessageType::Pair {
+            return Err(Self::Error::MsgType(header.message_type));
+        }
+
+        // the peer isn't authenticated yet, every field is checked against what is left of the frame
+        let mut body = split_body(&header, src)?;
+        ensure(&body, 1)?;
+        match body.get_u8() {
+            0 => {
+                ensure(&body, 1)?;
+                let code = body.get_u8() != 0;
+                let metadata = get_metadata(&mut body)?;
+                Ok(Some(Pairing::Request { metadata, code }))
+            }
+            1 => Ok(Some(Pairing::Ready)),
+            2 => {
+                ensure(&body, 2)?;
+                let secret_length = body.get_u16();
+                ensure(&body, secret_length.into())?;
+                let secret_bytes = body.split_to(secret_length.into());
+                let secret = String::from_utf8(secret_bytes.to_vec())?;
+                Ok(Some(Pairing::Accept(secret)))
+            }
+            3 => Ok(Some(Pairing::Reject)),
+            4 => {
+                ensure(&body, 4)?;
+                Ok(Some(Pairing::Failure(body.get_u32())))
+            }
+            5 => {
+                ensure(&body, PAIRING_KEY_LEN)?;
+                Ok(Some(Pairing::Spake(
+                    body.split_to(PAIRING_KEY_LEN).to_vec(),
+                )))
+            }
+            6 => {
+                ensure(&body, PAIRING_KEY_LEN)?;
+                Ok(Some(Pairing::Confirm(
+                    body.split_to(PAIRING_KEY_LEN).to_vec(),
+                )))
+            }
+            x => Err(Self::Error::Enum(x.into())),
+        }
+    }
+}
+
+impl Encoder<Pairing> for PairingCodec {
+    type Error = err::ParseError;
+
+    fn encode(&mut self, item: Pairing, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        HeaderCodec.encode(Header::new(MessageType::Pair, &item), dst)?;
+        match item {
+            Pairing::Request { metadata, code } => {
+                dst.put_u8(0);
+                dst.put_u8(code.into());
+                put_metadata(&metadata, dst);
+            }
+            Pairing::Ready => {
+                dst.put_u8(1);
+            }
+            Pairing::Accept(secret) => {
+                dst.put_u8(2);
+                dst.put_u16(secret.len().try_into().unwrap());
+                dst.put(secret.as_bytes());
+            }
+            Pairing::Reject => {
+                dst.put_u8(3);
+            }
+            Pairing::Failure(code) => {
+                dst.put_u8(4);
+                dst.put_u32(code);
+            }
+            Pairing::Spake(message) => {
+                dst.put_u8(5);
+                dst.put(&message[..PAIRING_KEY_LEN]);
+            }
+            Pairing::Confirm(tag) => {
+                dst.put_u8(6);
+                dst.put(&tag[..PAIRING_KEY_LEN]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits the body of the frame off the source, so a frame can't claim bytes of the next one
+fn split_body(header: &Header, src: &mut BytesMut) -> Result<BytesMut, err::ParseError> {
+    let len = usize::from(header.length)
+        .checked_sub(usize::from(header.len()))
+        .ok_or(err::ParseError::Truncated)?;
+    Ok(src.split_to(len))
+}
+
+/// Fails if fewer than the given number of bytes are left in the frame
+fn ensure(src: &BytesMut, len: usize) -> Result<(), err::ParseError> {
+    if src.len() < len {
+        return Err(err::ParseError::Truncated);
+    }
+    Ok(())
+}
+
 pub struct HeaderCodec;
 
 impl Decoder for HeaderCodec {
@@ -279,11 +626,15 @@ mod tests {
 
     use super::{DiscoveryCodec, SIGNATURE};
     use crate::{
+        err::ParseError,
         event::DiscoveryEvent,
         peer::{PeerId, PeerMetadata},
-        proto::{Connection, ConnectionCodec},
+        proto::{
+            Capabilities, Connection, ConnectionCodec, Pairing, PairingCodec, Stream, StreamCodec,
+            PROTOCOL_VERSION,
+        },
     };
-    use bytes::{BufMut, BytesMut};
+    use bytes::{BufMut, Bytes, BytesMut};
     use std::{
         fmt::Debug,
         net::{Ipv4Addr, SocketAddr, SocketAddrV4},
@@ -420,22 +771,35 @@ mod tests {
         let mut src = BytesMut::new();
 
         src.put(&SIGNATURE[..]);
-        src.put_u16(73 + 5); // length
+        src.put_u16(79 + 5); // length
         src.put_u8(2); // type
         src.put_u8(0); // connect type
         src.put(&b"0123456789012345678901234567890123456789"[..]); // peer id
-        src.put(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]); // hmac
+        src.put(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]); // nonce
+        src.put_u16(1); // version
+        src.put_u32(3); // capabilities
         let mut result = consume(&mut decoder, &mut src);
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Request { id, tag })) = result.pop() else {
+        let Some(Some(Connection::Request {
+            id,
+            nonce,
+            version,
+            caps,
+        })) = result.pop()
+        else {
             panic!("invalid frame");
         };
         assert_eq!("0123456789012345678901234567890123456789", id.to_string());
+        assert_eq!(1, version);
+        assert_eq!(
+            Capabilities::TLS.bits() | Capabilities::MUX.bits(),
+            caps.bits()
+        );
         assert_eq!(
             "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
-            String::from_utf8(tag).unwrap()
+            String::from_utf8(nonce).unwrap()
         );
     }
 
@@ -445,17 +809,32 @@ mod tests {
         let mut src = BytesMut::new();
 
         src.put(&SIGNATURE[..]);
-        src.put_u16(33 + 5); // length
+        src.put_u16(71 + 5); // length
         src.put_u8(2); // type
         src.put_u8(1); // connect type
+        src.put(&b"ScT23KfndGTb23DK2JL8JPHY5ManeQT0"[..]); // nonce
         src.put(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]); // hmac
+        src.put_u16(1); // version
+        src.put_u32(1); // capabilities
         let mut result = consume(&mut decoder, &mut src);
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Response(tag))) = result.pop() else {
+        let Some(Some(Connection::Response {
+            nonce,
+            tag,
+            version,
+            caps,
+        })) = result.pop()
+        else {
             panic!("invalid frame");
         };
+        assert_eq!(1, version);
+        assert_eq!(Capabilities::TLS, caps);
+        assert_eq!(
+            "ScT23KfndGTb23DK2JL8JPHY5ManeQT0",
+            String::from_utf8(nonce).unwrap()
+        );
         assert_eq!(
             "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
             String::from_utf8(tag).unwrap()
@@ -468,16 +847,21 @@ mod tests {
         let mut src = BytesMut::new();
 
         src.put(&SIGNATURE[..]);
-        src.put_u16(1 + 5); // length
+        src.put_u16(33 + 5); // length
         src.put_u8(2); // type
         src.put_u8(2); // connect type
+        src.put(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]); // hmac
         let mut result = consume(&mut decoder, &mut src);
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteRequest)) = result.pop() else {
+        let Some(Some(Connection::CompleteRequest(tag))) = result.pop() else {
             panic!("invalid frame");
         };
+        assert_eq!(
+            "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
+            String::from_utf8(tag).unwrap()
+        );
     }
 
     #[test]
@@ -526,7 +910,9 @@ mod tests {
         let item = Connection::Request {
             id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
                 .unwrap(),
-            tag: Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]),
+            nonce: Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]),
+            version: PROTOCOL_VERSION,
+            caps: Capabilities::SUPPORTED,
         };
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
@@ -534,13 +920,21 @@ mod tests {
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Request { id, tag })) = result.pop() else {
+        let Some(Some(Connection::Request {
+            id,
+            nonce,
+            version,
+            caps,
+        })) = result.pop()
+        else {
             panic!("invalid frame");
         };
+        assert_eq!(PROTOCOL_VERSION, version);
+        assert_eq!(Capabilities::SUPPORTED, caps);
         assert_eq!("0123456789012345678901234567890123456789", id.to_string());
         assert_eq!(
             "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
-            String::from_utf8(tag).unwrap()
+            String::from_utf8(nonce).unwrap()
         );
     }
 
@@ -549,16 +943,33 @@ mod tests {
         let mut encoder = ConnectionCodec;
         let mut dst = BytesMut::new();
 
-        let item = Connection::Response(Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]));
+        let item = Connection::Response {
+            nonce: Vec::from(&b"ScT23KfndGTb23DK2JL8JPHY5ManeQT0"[..]),
+            tag: Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]),
+            version: PROTOCOL_VERSION,
+            caps: Capabilities::SUPPORTED,
+        };
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
 
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Response(tag))) = result.pop() else {
+        let Some(Some(Connection::Response {
+            nonce,
+            tag,
+            version,
+            caps,
+        })) = result.pop()
+        else {
             panic!("invalid frame");
         };
+        assert_eq!(PROTOCOL_VERSION, version);
+        assert_eq!(Capabilities::SUPPORTED, caps);
+        assert_eq!(
+            "ScT23KfndGTb23DK2JL8JPHY5ManeQT0",
+            String::from_utf8(nonce).unwrap()
+        );
         assert_eq!(
             "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
             String::from_utf8(tag).unwrap()
@@ -570,16 +981,20 @@ mod tests {
         let mut encoder = ConnectionCodec;
         let mut dst = BytesMut::new();
 
-        let item = Connection::CompleteRequest;
+        let item = Connection::CompleteRequest(Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]));
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
 
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteRequest)) = result.pop() else {
+        let Some(Some(Connection::CompleteRequest(tag))) = result.pop() else {
             panic!("invalid frame");
         };
+        assert_eq!(
+            "0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT",
+            String::from_utf8(tag).unwrap()
+        );
     }
 
     #[test]
@@ -616,4 +1031,255 @@ mod tests {
         };
         assert_eq!(2001, code);
     }
+
+    #[test]
+    fn decode_stream_data() {
+        let mut decoder = StreamCodec;
+        let mut src = BytesMut::new();
+
+        src.put(&SIGNATURE[..]);
+        src.put_u16(9 + 5 + 1 + 4); // length
+        src.put_u8(3); // type
+        src.put_u8(1); // stream type
+        src.put_u32(7); // stream id
+        src.put(&b"some data"[..]); // data
+        let mut result = consume(&mut decoder, &mut src);
+
+        assert_eq!(0, src.len());
+        assert_eq!(1, result.len());
+        let Some(Some(Stream::Data(7, data))) = result.pop() else {
+            panic!("invalid frame");
+        };
+        assert_eq!(b"some data"[..], data[..]);
+    }
+
+    #[test]
+    fn decode_short_stream_frames() {
+        let mut decoder = StreamCodec;
+        for length in [5, 5 + 1, 5 + 1 + 4] {
+            let mut src = BytesMut::new();
+            src.put(&SIGNATURE[..]);
+            src.put_u16(length); // length
+            src.put_u8(3); // type
+            src.put_u8(2); // stream type, a window needs a credit after the id
+            src.put_u32(7); // stream id
+            src.put_u32(9); // the next frame
+            assert!(matches!(
+                decoder.decode(&mut src),
+                Err(ParseError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn encode_stream_frames() {
+        let mut encoder = StreamCodec;
+        let mut dst = BytesMut::new();
+
+        let items = vec![
+            Stream::Open(1),
+            Stream::Data(1, Bytes::from_static(b"some data")),
+            Stream::Window(1, 9),
+            Stream::Close(1),
+            Stream::Reset(2),
+        ];
+        for item in items {
+            encoder.encode(item, &mut dst).expect("Error Encoding");
+        }
+
+        let result = consume(&mut encoder, &mut dst);
+        assert_eq!(0, dst.len());
+        assert_eq!(
+            vec![
+                Some(Stream::Open(1)),
+                Some(Stream::Data(1, Bytes::from_static(b"some data"))),
+                Some(Stream::Window(1, 9)),
+                Some(Stream::Close(1)),
+                Some(Stream::Reset(2)),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn decode_pair_accept() {
+        let mut decoder = PairingCodec;
+        let mut src = BytesMut::new();
+
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 2 + 6 + 5); // length
+        src.put_u8(4); // type
+        src.put_u8(2); // pair type
+        src.put_u16(6); // secret length
+        src.put(&b"secret"[..]); // secret
+        let mut result = consume(&mut decoder, &mut src);
+
+        assert_eq!(0, src.len());
+        assert_eq!(1, result.len());
+        let Some(Some(Pairing::Accept(secret))) = result.pop() else {
+            panic!("invalid frame");
+        };
+        assert_eq!("secret", secret);
+    }
+
+    #[test]
+    fn decode_malformed_pair_frames() {
+        let mut decoder = PairingCodec;
+
+        // the secret claims more bytes than the frame holds
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 2 + 5); // length
+        src.put_u8(4); // type
+        src.put_u8(2); // pair type
+        src.put_u16(6); // secret length
+        src.put(&b"secret"[..]); // secret
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ParseError::Truncated)
+        ));
+
+        // a spake message cut short
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 16 + 5); // length
+        src.put_u8(4); // type
+        src.put_u8(5); // pair type
+        src.put(&[1; 16][..]); // message
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ParseError::Truncated)
+        ));
+
+        // a secret which isn't utf8
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 2 + 2 + 5); // length
+        src.put_u8(4); // type
+        src.put_u8(2); // pair type
+        src.put_u16(2); // secret length
+        src.put(&[0xc3, 0x28][..]); // secret
+        assert!(matches!(decoder.decode(&mut src), Err(ParseError::Utf8(_))));
+    }
+
+    #[test]
+    fn decode_malformed_connect_frames() {
+        let mut decoder = ConnectionCodec;
+
+        // a request cut short after the peer id
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 40 + 5); // length
+        src.put_u8(2); // type
+        src.put_u8(0); // connection type
+        src.put(&b"0123456789012345678901234567890123456789"[..]); // peer id
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ParseError::Truncated)
+        ));
+
+        // a peer id which isn't utf8
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 40 + 32 + 2 + 4 + 5); // length
+        src.put_u8(2); // type
+        src.put_u8(0); // connection type
+        src.put(&[0xff; 40][..]); // peer id
+        src.put(&[0; 32 + 2 + 4][..]); // nonce, version & capabilities
+        assert!(matches!(decoder.decode(&mut src), Err(ParseError::Utf8(_))));
+
+        // a peer id with invalid characters
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 40 + 32 + 2 + 4 + 5); // length
+        src.put_u8(2); // type
+        src.put_u8(0); // connection type
+        src.put(&[b'-'; 40][..]); // peer id
+        src.put(&[0; 32 + 2 + 4][..]); // nonce, version & capabilities
+        assert!(matches!(decoder.decode(&mut src), Err(ParseError::Id(_))));
+
+        // a failure without its code doesn't take the next frame's bytes
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 5); // length
+        src.put_u8(2); // type
+        src.put_u8(4); // connection type
+        src.put_u32(2001); // the next frame
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ParseError::Truncated)
+        ));
+        assert_eq!(4, src.len());
+
+        // a response cut short
+        let mut src = BytesMut::new();
+        src.put(&SIGNATURE[..]);
+        src.put_u16(1 + 32 + 5); // length
+        src.put_u8(2); // type
+        src.put_u8(1); // connection type
+        src.put(&[1; 32][..]); // nonce
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn encode_pair_frames() {
+        let mut encoder = PairingCodec;
+        let mut dst = BytesMut::new();
+
+        let metadata = PeerMetadata {
+            name: "test phone".to_string(),
+            typ: crate::peer::DeviceType::AppleiPhone,
+            id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
+                .unwrap(),
+            addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5001)),
+        };
+        let items = vec![
+            Pairing::Request {
+                metadata: metadata.clone(),
+                code: true,
+            },
+            Pairing::Ready,
+            Pairing::Accept("secret".to_string()),
+            Pairing::Reject,
+            Pairing::Failure(2001),
+            Pairing::Spake(vec![1; 32]),
+            Pairing::Confirm(vec![2; 32]),
+        ];
+        for item in items {
+            encoder.encode(item, &mut dst).expect("Error Encoding");
+        }
+
+        let result = consume(&mut encoder, &mut dst);
+        assert_eq!(0, dst.len());
+        assert_eq!(
+            vec![
+                Some(Pairing::Request {
+                    metadata,
+                    code: true
+                }),
+                Some(Pairing::Ready),
+                Some(Pairing::Accept("secret".to_string())),
+                Some(Pairing::Reject),
+                Some(Pairing::Failure(2001)),
+                Some(Pairing::Spake(vec![1; 32])),
+                Some(Pairing::Confirm(vec![2; 32])),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn capabilities_intersect() {
+        let remote =
+            Capabilities::from_bits(Capabilities::TLS.bits() | Capabilities::COMPRESSION.bits());
+        let negotiated = Capabilities::SUPPORTED.intersection(remote);
+        assert!(negotiated.contains(Capabilities::TLS));
+        assert!(!negotiated.contains(Capabilities::MUX));
+        assert!(!negotiated.contains(Capabilities::COMPRESSION));
+        assert!(!negotiated.contains(Capabilities::REQUIRED));
+        assert!(Capabilities::SUPPORTED.contains(Capabilities::REQUIRED));
+    }
 }
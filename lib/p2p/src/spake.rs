@@ -0,0 +1,277 @@
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::Identity,
+};
+use rand::Rng;
+use ring::digest;
+
+use crate::{err, hmac, peer::PeerId};
+
+/// The length of a SPAKE2 message, a compressed ristretto point
+const MESSAGE_LEN: usize = 32;
+
+/// The seeds of the two fixed points which blind the messages of each side.
+/// Nobody knows the discrete log of the points as they are derived from a hash.
+const SEED_M: &[u8] = b"fd-p2p spake2 M";
+const SEED_N: &[u8] = b"fd-p2p spake2 N";
+
+/// Which side of the exchange the peer is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// the peer which requested to pair & shows the code
+    Client,
+    /// the peer which was asked to pair & the user entered the code on
+    Host,
+}
+
+/// One side of a SPAKE2 exchange over the ristretto255 group. Both peers start with the same short code,
+/// exchange a single message each and end up with the same strong key if and only if the codes matched.
+/// An eavesdropper learns nothing about the code and an active attacker can only test one code per exchange.
+///
+/// This follows SPAKE2 as specified in RFC 9382 instead of using a crate, the only dependency is the group
+/// arithmetic of curve25519-dalek, and each deviation from the RFC is one it allows:
+/// - ristretto255 is a prime order group, so there are no low order points & no cofactor to clear, any message
+///   which decompresses is a valid element. A message of the identity, or one which makes the shared point the
+///   identity, is rejected as the RFC requires.
+/// - `M` & `N` are hashed to the group with the ristretto map (RFC 9496), so nobody knows their discrete logs.
+/// - the key is the hash of the transcript of section 3.3 with both peer ids as the identities, every part is
+///   prefixed with its length, and each side proves it derived the same key with an HMAC confirmation (section 4)
+///   before anything is derived from it.
+pub(crate) struct Spake2 {
+    role: Role,
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; MESSAGE_LEN],
+}
+
+impl Spake2 {
+    /// start the exchange, the message has to be sent to the other peer
+    pub(crate) fn start(role: Role, code: &str) -> Self {
+        let password = hash_to_scalar(code.as_bytes());
+        let mut wide = [0u8; 64];
+        rand::thread_rng().fill(&mut wide[..]);
+        let secret = Scalar::from_bytes_mod_order_wide(&wide);
+        let blind = match role {
+            Role::Client => hash_to_point(SEED_M),
+            Role::Host => hash_to_point(SEED_N),
+        };
+        let message = (RISTRETTO_BASEPOINT_POINT * secret + blind * password)
+            .compress()
+            .to_bytes();
+        Self {
+            role,
+            password,
+            secret,
+            message,
+        }
+    }
+
+    /// the message to send to the other peer
+    pub(crate) fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// finish the exchange with the other peer's message, the peer ids bind the key to both peers
+    pub(crate) fn finish(
+        self,
+        other: &[u8],
+        client: &PeerId,
+        host: &PeerId,
+    ) -> Result<SharedKey, err::ConnError> {
+        let point = CompressedRistretto::from_slice(other)
+            .ok()
+            .and_then(|p| p.decompress())
+            .filter(|p| *p != RistrettoPoint::identity())
+            .ok_or(err::ConnError::Auth)?;
+        let (unblind, client_msg, host_msg) = match self.role {
+            Role::Client => (hash_to_point(SEED_N), &self.message[..], other),
+            Role::Host => (hash_to_point(SEED_M), other, &self.message[..]),
+        };
+        let shared = (point - unblind * self.password) * self.secret;
+        if shared == RistrettoPoint::identity() {
+            return Err(err::ConnError::Auth);
+        }
+
+        // the key is derived from the whole transcript of the exchange
+        let mut transcript = digest::Context::new(&digest::SHA256);
+        for part in [
+            client.as_bytes(),
+            host.as_bytes(),
+            client_msg,
+            host_msg,
+            shared.compress().as_bytes(),
+            self.password.as_bytes(),
+        ] {
+            transcript.update(&(part.len() as u64).to_be_bytes());
+            transcript.update(part);
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(transcript.finish().as_ref());
+        Ok(SharedKey(key))
+    }
+}
+
+/// The key both peers derived from the exchange. It is only the same on both peers if they used the same code,
+/// which each peer proves to the other with a confirmation before the key is used.
+pub(crate) struct SharedKey([u8; 32]);
+
+impl SharedKey {
+    /// the confirmation the given side sends to prove it derived the same key
+    pub(crate) fn confirmation(&self, role: Role) -> Vec<u8> {
+        hmac::sign(&self.0, confirmation_label(role))
+            .as_ref()
+            .to_vec()
+    }
+
+    /// verify the confirmation sent by the given side
+    pub(crate) fn verify(&self, role: Role, tag: &[u8]) -> Result<(), err::ConnError> {
+        Ok(hmac::verify(&self.0, confirmation_label(role), tag)?)
+    }
+
+    /// the long-term secret shared by the paired peers
+    pub(crate) fn secret(&self) -> String {
+        hmac::sign(&self.0, b"fd-p2p spake2 secret")
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+fn confirmation_label(role: Role) -> &'static [u8] {
+    match role {
+        Role::Client => b"fd-p2p spake2 client confirmation",
+        Role::Host => b"fd-p2p spake2 host confirmation",
+    }
+}
+
+fn hash_to_point(seed: &[u8]) -> RistrettoPoint {
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(digest::digest(&digest::SHA512, seed).as_ref());
+    RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+fn hash_to_scalar(code: &[u8]) -> Scalar {
+    let mut context = digest::Context::new(&digest::SHA512);
+    context.update(b"fd-p2p spake2 password");
+    context.update(code);
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(context.finish().as_ref());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::{ristretto::RistrettoPoint, traits::Identity as _};
+
+    use super::{hash_to_point, hash_to_scalar, Role, SharedKey, Spake2, SEED_N};
+    use crate::peer::{Identity, PeerId};
+
+    fn id() -> PeerId {
+        PeerId::from_cert(&Identity::default().into_rustls().0)
+    }
+
+    fn exchange(client_code: &str, host_code: &str) -> (SharedKey, SharedKey) {
+        let (client_id, host_id) = (id(), id());
+        let client = Spake2::start(Role::Client, client_code);
+        let host = Spake2::start(Role::Host, host_code);
+        let (client_msg, host_msg) = (client.message().to_vec(), host.message().to_vec());
+        let client_key = client.finish(&host_msg, &client_id, &host_id).unwrap();
+        let host_key = host.finish(&client_msg, &client_id, &host_id).unwrap();
+        (client_key, host_key)
+    }
+
+    #[test]
+    fn spake2_same_code_derives_same_key() {
+        let (client, host) = exchange("123456", "123456");
+        assert!(host
+            .verify(Role::Client, &client.confirmation(Role::Client))
+            .is_ok());
+        assert!(client
+            .verify(Role::Host, &host.confirmation(Role::Host))
+            .is_ok());
+        assert_eq!(client.secret(), host.secret());
+        assert_eq!(64, client.secret().len());
+    }
+
+    #[test]
+    fn spake2_different_code_fails_confirmation() {
+        let (client, host) = exchange("123456", "654321");
+        assert!(host
+            .verify(Role::Client, &client.confirmation(Role::Client))
+            .is_err());
+        assert!(client
+            .verify(Role::Host, &host.confirmation(Role::Host))
+            .is_err());
+        assert_ne!(client.secret(), host.secret());
+    }
+
+    #[test]
+    fn spake2_rejects_invalid_message() {
+        let client = Spake2::start(Role::Client, "123456");
+        assert!(client.finish(&[0xff; 32], &id(), &id()).is_err());
+    }
+
+    #[test]
+    fn spake2_rejects_identity() {
+        let identity = RistrettoPoint::identity().compress().to_bytes();
+        let client = Spake2::start(Role::Client, "123456");
+        assert!(client.finish(&identity, &id(), &id()).is_err());
+        let host = Spake2::start(Role::Host, "123456");
+        assert!(host.finish(&identity, &id(), &id()).is_err());
+
+        // a message which only holds the blinding makes the shared point the identity
+        let blinding = (hash_to_point(SEED_N) * hash_to_scalar(b"123456"))
+            .compress()
+            .to_bytes();
+        let client = Spake2::start(Role::Client, "123456");
+        assert!(client.finish(&blinding, &id(), &id()).is_err());
+    }
+
+    #[test]
+    fn spake2_each_code_only_matches_itself() {
+        for code in ["123457", "023456", "1234560", ""] {
+            let (client, host) = exchange("123456", code);
+            assert!(host
+                .verify(Role::Client, &client.confirmation(Role::Client))
+                .is_err());
+            assert!(client
+                .verify(Role::Host, &host.confirmation(Role::Host))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn spake2_key_is_bound_to_the_exchange() {
+        let (client_id, host_id) = (id(), id());
+        let client = Spake2::start(Role::Client, "123456");
+        let host = Spake2::start(Role::Host, "123456");
+        let (client_msg, host_msg) = (client.message().to_vec(), host.message().to_vec());
+        let client_key = client.finish(&host_msg, &client_id, &host_id).unwrap();
+
+        // the host thinks it talks to another peer, e.g. a relay between the peers
+        let host_key = host.finish(&client_msg, &id(), &host_id).unwrap();
+        assert!(host_key
+            .verify(Role::Client, &client_key.confirmation(Role::Client))
+            .is_err());
+
+        // a confirmation can't be reflected back to the side which sent it
+        let (client, host) = exchange("123456", "123456");
+        assert!(client
+            .verify(Role::Host, &client.confirmation(Role::Client))
+            .is_err());
+        assert!(host
+            .verify(Role::Client, &host.confirmation(Role::Host))
+            .is_err());
+
+        // both peers taking the same side never agree
+        let a = Spake2::start(Role::Client, "123456");
+        let b = Spake2::start(Role::Client, "123456");
+        let (a_msg, b_msg) = (a.message().to_vec(), b.message().to_vec());
+        let a_key = a.finish(&b_msg, &client_id, &host_id).unwrap();
+        let b_key = b.finish(&a_msg, &client_id, &host_id).unwrap();
+        assert_ne!(a_key.secret(), b_key.secret());
+    }
+}
@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Duration, time::SystemTime};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, ClientConfig, DistinguishedNames, PrivateKey, ServerConfig, ServerName,
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tracing::error;
+
+use crate::{err, manager::P2pManager, peer::PeerId};
+
+/// The server name presented during the TLS handshake. Peers are verified by their [PeerId], not by name.
+const SERVER_NAME: &str = "fd-p2p-identity";
+
+/// Verifies the remote peer's certificate belongs to the [PeerId] we expect to be talking to.
+/// The certificates are self-signed so there is no chain to validate, instead the [PeerId] is
+/// derived from the certificate and compared. The handshake signatures are still checked by rustls
+/// which proves the remote peer owns the private key of the certificate.
+struct PeerIdVerifier(PeerId);
+
+impl PeerIdVerifier {
+    fn verify(&self, end_entity: &Certificate) -> Result<(), rustls::Error> {
+        let id = PeerId::from_cert(end_entity);
+        if id != self.0 {
+            return Err(rustls::Error::InvalidCertificateData(format!(
+                "expected peer {} but the certificate belongs to {}",
+                self.0, id
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for PeerIdVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for PeerIdVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// build the tls config used by the client, which presents our identity and only trusts the remote peer
+pub(crate) fn client_config(
+    identity: &(Certificate, PrivateKey),
+    remote: PeerId,
+) -> Result<ClientConfig, rustls::Error> {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PeerIdVerifier(remote)))
+        .with_single_cert(vec![identity.0.clone()], identity.1.clone())
+}
+
+/// build the tls config used by the host, which presents our identity and requires the remote peer's identity
+pub(crate) fn server_config(
+    identity: &(Certificate, PrivateKey),
+    remote: PeerId,
+) -> Result<ServerConfig, rustls::Error> {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(PeerIdVerifier(remote)))
+        .with_single_cert(vec![identity.0.clone()], identity.1.clone())
+}
+
+/// upgrade the tcp connection to tls as the client
+pub(crate) async fn connect(
+    manager: &Arc<P2pManager>,
+    conn: TcpStream,
+    remote: &PeerId,
+) -> Result<TlsStream<TcpStream>, err::ConnError> {
+    let config = client_config(&manager.identity, remote.clone())?;
+    let name = ServerName::try_from(SERVER_NAME).expect("the server name is a valid dns name");
+    let handshake = TlsConnector::from(Arc::new(config)).connect(name, conn);
+    let Ok(result) = timeout(Duration::from_secs(1), handshake).await else {
+        error!("peer timed out during the tls handshake");
+        return Err(err::ConnError::Timeout);
+    };
+    Ok(result.map_err(handshake_error)?.into())
+}
+
+/// upgrade the tcp connection to tls as the host
+pub(crate) async fn accept(
+    manager: &Arc<P2pManager>,
+    conn: TcpStream,
+    remote: &PeerId,
+) -> Result<TlsStream<TcpStream>, err::ConnError> {
+    let config = server_config(&manager.identity, remote.clone())?;
+    let handshake = TlsAcceptor::from(Arc::new(config)).accept(conn);
+    let Ok(result) = timeout(Duration::from_secs(1), handshake).await else {
+        error!("peer timed out during the tls handshake");
+        return Err(err::ConnError::Timeout);
+    };
+    Ok(result.map_err(handshake_error)?.into())
+}
+
+/// tokio-rustls reports tls failures as i/o errors, unwrap them so a bad certificate is reported as such
+fn handshake_error(e: std::io::Error) -> err::ConnError {
+    error!("tls handshake failed: {:?}", e);
+    match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(tls) => err::ConnError::Tls(tls.clone()),
+        None => err::ConnError::Parse(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::ServerName;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::{client_config, server_config, SERVER_NAME};
+    use crate::peer::{Identity, PeerId};
+
+    /// perform a handshake between two peers over an in-memory stream, returning whether each side succeeded
+    async fn handshake(
+        client: Identity,
+        server: Identity,
+        client_expects: PeerId,
+        server_expects: PeerId,
+    ) -> (bool, bool) {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let name = ServerName::try_from(SERVER_NAME).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            client_config(&client.into_rustls(), client_expects).unwrap(),
+        ));
+        let acceptor = TlsAcceptor::from(Arc::new(
+            server_config(&server.into_rustls(), server_expects).unwrap(),
+        ));
+        let (c, s) = tokio::join!(
+            connector.connect(name, client_io),
+            acceptor.accept(server_io)
+        );
+        (c.is_ok(), s.is_ok())
+    }
+
+    fn id(identity: &Identity) -> PeerId {
+        PeerId::from_cert(&identity.clone().into_rustls().0)
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_with_expected_peers() {
+        let (client, server) = (Identity::default(), Identity::default());
+        let (c, s) = handshake(client.clone(), server.clone(), id(&server), id(&client)).await;
+        assert!(c);
+        assert!(s);
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_rejects_unexpected_server() {
+        let (client, server) = (Identity::default(), Identity::default());
+        let (c, _) = handshake(client.clone(), server, id(&client), id(&client)).await;
+        assert!(!c);
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_rejects_unexpected_client() {
+        let (client, server) = (Identity::default(), Identity::default());
+        let (_, s) = handshake(client, server.clone(), id(&server), id(&server)).await;
+        assert!(!s);
+    }
+}
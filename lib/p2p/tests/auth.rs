@@ -0,0 +1,110 @@
+use std::{error::Error, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use p2p::{
+    err::ConnError,
+    event::P2pEvent,
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::{PeerCandidate, PeerId, PeerMetadata},
+    proto::{Capabilities, Connection, ConnectionCodec, PROTOCOL_VERSION},
+};
+use tokio::{net::TcpStream, time::sleep};
+use tokio_util::codec::Framed;
+
+use crate::common::*;
+
+mod common;
+
+fn create_config(name: &str) -> P2pConfig {
+    P2pConfig {
+        identity: create_identity(),
+        device: p2p::peer::DeviceType::LinuxDevice,
+        name: String::from(name),
+        multicast: create_multicast_addr(),
+        p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
+    }
+}
+
+#[tokio::test]
+async fn peers_with_different_secrets_fail_to_connect() -> Result<(), Box<dyn Error>> {
+    let auth_a = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    let auth_b = PairingAuthenticator::new(b"123ABCThisIsAnotherSecretShhh!".to_vec())?;
+
+    let (manager_a, mut rx_a) = P2pManager::new(create_config("Tester's laptop")).await?;
+    let (manager_b, _rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    let b = manager_b.get_metadata();
+    manager_a.add_known_peer(PeerCandidate::new(b, auth_b));
+    manager_b.add_known_peer(PeerCandidate::new(manager_a.get_metadata(), auth_a));
+
+    // node A discovers node B
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let Some(P2pEvent::PeerDiscovered(_)) =
+        recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await
+    else {
+        panic!("node a did not discover node b");
+    };
+
+    // node B can't answer node A's challenge without the same secret
+    let result = manager_a.connect_to_peer(&b.id).await;
+    assert!(matches!(result, Err(ConnError::Auth)));
+    assert!(!manager_a.is_connected(&b.id));
+
+    Ok(())
+}
+
+/// send a connection request with the given version & capabilities, returning the host's response
+async fn offer(
+    host: &PeerMetadata,
+    client: &PeerId,
+    version: u16,
+    caps: Capabilities,
+) -> Connection {
+    let conn = TcpStream::connect(host.addr).await.unwrap();
+    let mut frame = Framed::new(conn, ConnectionCodec);
+    frame
+        .send(Connection::Request {
+            id: client.clone(),
+            nonce: vec![7; 32],
+            version,
+            caps,
+        })
+        .await
+        .unwrap();
+    frame.next().await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn peers_with_incompatible_versions_are_rejected() -> Result<(), Box<dyn Error>> {
+    let (manager_b, _rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    let b = manager_b.get_metadata();
+    let a = PeerMetadata {
+        id: PeerId::from_cert(&create_identity().into_rustls().0),
+        ..Default::default()
+    };
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    manager_b.add_known_peer(PeerCandidate::new(&a, auth));
+
+    // a version older than node B supports
+    let response = offer(b, &a.id, 0, Capabilities::SUPPORTED).await;
+    assert!(matches!(response, Connection::Failure(2004)));
+
+    // a peer without the required capabilities
+    let response = offer(b, &a.id, PROTOCOL_VERSION, Capabilities::TLS).await;
+    assert!(matches!(response, Connection::Failure(2004)));
+
+    // a newer peer with extra capabilities speaks node B's version
+    let caps = Capabilities::from_bits(u32::MAX);
+    let response = offer(b, &a.id, PROTOCOL_VERSION + 1, caps).await;
+    let Connection::Response { version, caps, .. } = response else {
+        panic!("node b did not respond to the newer peer");
+    };
+    assert_eq!(PROTOCOL_VERSION, version);
+    assert_eq!(Capabilities::SUPPORTED, caps);
+
+    Ok(())
+}
@@ -1,6 +1,17 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
 
-use p2p::{discovery::DISCOVERY_MULTICAST, peer::PeerId};
+use p2p::{
+    discovery::DISCOVERY_MULTICAST,
+    event::P2pEvent,
+    peer::{Identity, PeerId},
+};
+use tokio::{
+    sync::mpsc::UnboundedReceiver,
+    time::{timeout_at, Instant},
+};
 
 pub fn create_p2p_addr() -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
@@ -10,10 +21,32 @@ pub fn create_multicast_addr() -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50692))
 }
 
-pub fn create_peer_id_one() -> PeerId {
-    PeerId::from_string("0123456789012345678901234567890123456789".to_string()).unwrap()
+pub fn create_identity() -> Identity {
+    Identity::default()
 }
 
-pub fn create_peer_id_two() -> PeerId {
-    PeerId::from_string("QWERTYUIOPQWERTYUIOPQWERTYUIOPQWERTYUIOP".to_string()).unwrap()
+/// wait for the next event about the given peer, the tests share the multicast group so events
+/// about the peers of other tests are skipped
+#[allow(dead_code)]
+pub async fn recv_about(
+    rx: &mut UnboundedReceiver<P2pEvent>,
+    id: &PeerId,
+    duration: Duration,
+) -> Option<P2pEvent> {
+    let deadline = Instant::now() + duration;
+    loop {
+        let event = timeout_at(deadline, rx.recv()).await.ok()??;
+        let about = match &event {
+            P2pEvent::PeerDiscovered(meta)
+            | P2pEvent::NearbyDiscovered(meta)
+            | P2pEvent::PeerUpdated(meta)
+            | P2pEvent::PeerPaired { peer: meta, .. } => &meta.id,
+            P2pEvent::PairingRequested(req) => &req.peer.id,
+            P2pEvent::PeerLost(id) | P2pEvent::PeerDisconnected(id) => id,
+            P2pEvent::PeerConnected(peer) => &peer.id,
+        };
+        if about == id {
+            return Some(event);
+        }
+    }
 }
@@ -0,0 +1,164 @@
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use futures::SinkExt;
+use p2p::{
+    discovery,
+    event::{DiscoveryEvent, P2pEvent},
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::PeerCandidate,
+    proto::DiscoveryCodec,
+};
+use tokio::time::sleep;
+use tokio_util::udp::UdpFramed;
+
+use crate::common::*;
+
+mod common;
+
+fn create_config(name: &str) -> P2pConfig {
+    P2pConfig {
+        identity: create_identity(),
+        device: p2p::peer::DeviceType::LinuxDevice,
+        name: String::from(name),
+        multicast: create_multicast_addr(),
+        p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(1),
+    }
+}
+
+#[tokio::test]
+async fn discovered_peer_is_lost_after_ttl() -> Result<(), Box<dyn Error>> {
+    let shared_secret = b"123ABCThisIsSuperSecretShhhh!";
+    let auth_a = PairingAuthenticator::new(shared_secret.to_vec())?;
+    let auth_b = PairingAuthenticator::new(shared_secret.to_vec())?;
+
+    let (manager_a, mut rx_a) = P2pManager::new(create_config("Tester's laptop")).await?;
+    let (manager_b, _rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    manager_a.add_known_peer(PeerCandidate::new(manager_b.get_metadata(), auth_b));
+    manager_b.add_known_peer(PeerCandidate::new(manager_a.get_metadata(), auth_a));
+
+    // node A discovers node B
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let b = manager_b.get_metadata();
+    let Some(P2pEvent::PeerDiscovered(metadata)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not discover node b");
+    };
+    assert!(manager_a.is_discovered(&metadata.id));
+
+    // node B is lost once it stops responding for longer than the ttl
+    let Some(P2pEvent::PeerLost(lost)) = recv_about(&mut rx_a, &b.id, Duration::from_secs(3)).await else {
+        panic!("node a did not lose node b");
+    };
+    assert_eq!(metadata.id, lost);
+    assert!(!manager_a.is_discovered(&metadata.id));
+    assert!(manager_a.get_discovered_peers().is_empty());
+
+    // a lost known peer can still be connected to where it was last seen, e.g. after discovery stopped
+    let peer = manager_a.connect_to_peer(&b.id).await?;
+    assert_eq!(b.id, peer.id);
+    assert!(manager_a.is_connected(&b.id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn discovered_peer_is_updated() -> Result<(), Box<dyn Error>> {
+    let shared_secret = b"123ABCThisIsSuperSecretShhhh!";
+    let auth_a = PairingAuthenticator::new(shared_secret.to_vec())?;
+    let auth_b = PairingAuthenticator::new(shared_secret.to_vec())?;
+
+    let mut config = create_config("Tester's laptop");
+    config.peer_ttl = Duration::from_secs(60);
+    let (manager_a, mut rx_a) = P2pManager::new(config).await?;
+    let (manager_b, _rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    let (impostor, _rx_c) = P2pManager::new(create_config("Impostor")).await?;
+
+    // node A last reached node B at an address it has since moved away from
+    let b = manager_b.get_metadata();
+    let mut stale = b.clone();
+    stale.addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+    manager_a.add_known_peer(PeerCandidate::new(&stale, auth_b));
+    manager_b.add_known_peer(PeerCandidate::new(manager_a.get_metadata(), auth_a));
+
+    // node A discovers node B but doesn't trust the address of the presence response yet
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let Some(P2pEvent::PeerDiscovered(metadata)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not discover node b");
+    };
+    assert_eq!(stale, metadata);
+
+    // a spoofed presence response renames node B and points at another host
+    let mut moved = metadata.clone();
+    moved.name = String::from("Tester's renamed phone");
+    moved.addr = impostor.get_metadata().addr;
+    let multicast = create_multicast_addr();
+    let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast.port()));
+    let (socket, _) = discovery::multicast(&local, &multicast)?;
+    let mut socket = UdpFramed::new(socket, DiscoveryCodec);
+    socket
+        .send((DiscoveryEvent::PresenceResponse(moved.clone()), multicast))
+        .await?;
+
+    let Some(P2pEvent::PeerUpdated(updated)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not update node b");
+    };
+    assert_eq!(moved.name, updated.name);
+    assert_eq!(stale.addr, updated.addr);
+    assert_eq!(vec![updated.clone()], manager_a.get_discovered_peers());
+
+    // an unchanged presence response does not update the peer
+    socket
+        .send((DiscoveryEvent::PresenceResponse(moved), multicast))
+        .await?;
+    assert!(recv_about(&mut rx_a, &b.id, Duration::from_millis(200)).await.is_none());
+
+    // node A connects to node B at the address which presents its certificate and only then replaces the others
+    let peer = manager_a.connect_to_peer(&b.id).await?;
+    assert_eq!(b.id, peer.id);
+    let Some(P2pEvent::PeerUpdated(reached)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not update the address of node b");
+    };
+    assert_eq!(b.addr, reached.addr);
+    assert_eq!(updated.name, reached.name);
+    assert_eq!(vec![reached], manager_a.get_discovered_peers());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unpaired_peer_is_nearby() -> Result<(), Box<dyn Error>> {
+    let mut config = create_config("Tester's laptop");
+    config.peer_ttl = Duration::from_secs(60);
+    let (manager_a, mut rx_a) = P2pManager::new(config).await?;
+    let (manager_b, _rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+
+    // node A finds node B nearby but not as a discovered peer
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let b = manager_b.get_metadata();
+    let Some(P2pEvent::NearbyDiscovered(metadata)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not find node b nearby");
+    };
+    assert_eq!(b.clone(), metadata);
+    assert!(manager_a.get_nearby_peers().contains(b));
+    assert!(!manager_a.is_discovered(&b.id));
+
+    // node A can not connect to the unpaired node B
+    assert!(manager_a.connect_to_peer(&b.id).await.is_err());
+
+    // once paired node B is no longer nearby
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    manager_a.add_known_peer(PeerCandidate::new(b, auth));
+    assert!(!manager_a.get_nearby_peers().contains(b));
+
+    Ok(())
+}
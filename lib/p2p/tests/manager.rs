@@ -5,6 +5,7 @@ use p2p::{
     manager::{P2pConfig, P2pManager},
     pairing::PairingAuthenticator,
     peer::{ConnectionType, PeerCandidate},
+    proto::{Capabilities, PROTOCOL_VERSION},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::{sleep, timeout};
@@ -27,21 +28,27 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
 
     // node A setup
     let config = P2pConfig {
-        id: create_peer_id_one(),
+        identity: create_identity(),
         device: p2p::peer::DeviceType::Windows10Desktop,
         name: String::from("Tester's laptop"),
         multicast: create_multicast_addr(),
         p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
     };
     let (manager_a, mut rx_a) = P2pManager::new(config).await?;
 
     // node B setup
     let config = P2pConfig {
-        id: create_peer_id_two(),
+        identity: create_identity(),
         device: p2p::peer::DeviceType::AppleiPhone,
         name: String::from("Tester's phone"),
         multicast: create_multicast_addr(),
         p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
     };
     let (manager_b, mut rx_b) = P2pManager::new(config).await?;
 
@@ -68,10 +75,10 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
     let Ok(connected) = timeout(Duration::from_millis(10000),manager_a.connect_to_peer(&metadata.id)).await else {
         panic!("node a did not connect to node b");
     };
-    let mut proxy_to_b = connected?;
+    let proxy_to_b = connected?;
     assert!(manager_a.is_connected(&metadata_b.id));
 
-    let Ok(Some(P2pEvent::PeerConnected(mut proxy_to_a))) = timeout(Duration::from_millis(1000), rx_b.recv()).await else {
+    let Ok(Some(P2pEvent::PeerConnected(proxy_to_a))) = timeout(Duration::from_millis(1000), rx_b.recv()).await else {
         panic!("node b did not connect to node a");
     };
     let metadata_a = manager_a.get_metadata();
@@ -81,20 +88,59 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
     assert_eq!(ConnectionType::Client, proxy_to_b.conn_type);
     assert_eq!(ConnectionType::Server, proxy_to_a.conn_type);
 
-    // assert node A can send to node B
-    let mut buffer: [u8; 10] = [0; 10];
+    // assert both peers negotiated the same capabilities
+    assert_eq!(PROTOCOL_VERSION, proxy_to_b.version);
+    assert_eq!(Capabilities::SUPPORTED, proxy_to_b.caps);
+    assert_eq!(proxy_to_b.caps, proxy_to_a.caps);
 
-    proxy_to_b.conn.write_all(b"PING").await?;
-    let len = proxy_to_a.conn.read(&mut buffer[..]).await?;
+    // assert node A can open a stream to node B
+    let mut buffer: [u8; 10] = [0; 10];
+    let mut a_to_b = proxy_to_b.open_stream().await?;
+    a_to_b.write_all(b"PING").await?;
+    let Ok(Some(mut b_from_a)) = timeout(Duration::from_millis(1000), proxy_to_a.accept_stream()).await else {
+        panic!("node b did not accept a stream from node a");
+    };
+    let len = b_from_a.read(&mut buffer[..]).await?;
     assert_eq!(b"PING"[..], buffer[..len]);
 
-    // assert node B can send to node A
-    proxy_to_a.conn.write_all(b"PONG").await?;
-    let len = proxy_to_b.conn.read(&mut buffer[..]).await?;
+    // assert node B can send to node A on the same stream
+    b_from_a.write_all(b"PONG").await?;
+    let len = a_to_b.read(&mut buffer[..]).await?;
     assert_eq!(b"PONG"[..], buffer[..len]);
 
+    // assert node B can open a second stream to node A over the same connection
+    let mut b_to_a = proxy_to_a.open_stream().await?;
+    b_to_a.write_all(b"HELLO").await?;
+    let Ok(Some(mut a_from_b)) = timeout(Duration::from_millis(1000), proxy_to_b.accept_stream()).await else {
+        panic!("node a did not accept a stream from node b");
+    };
+    let len = a_from_b.read(&mut buffer[..]).await?;
+    assert_eq!(b"HELLO"[..], buffer[..len]);
+
+    // assert closing a stream does not close the others
+    drop(b_to_a);
+    let len = a_from_b.read(&mut buffer[..]).await?;
+    assert_eq!(0, len);
+    a_to_b.write_all(b"PING").await?;
+    let len = b_from_a.read(&mut buffer[..]).await?;
+    assert_eq!(b"PING"[..], buffer[..len]);
+
+    // assert node A reuses the connection to node B
+    let Ok(reused) = timeout(Duration::from_millis(1000), manager_a.connect_to_peer(&metadata.id)).await else {
+        panic!("node a did not reuse the connection to node b");
+    };
+    let reused = reused?;
+    let mut a_to_b_again = reused.open_stream().await?;
+    a_to_b_again.write_all(b"AGAIN").await?;
+    let Ok(Some(mut b_from_a_again)) = timeout(Duration::from_millis(1000), proxy_to_a.accept_stream()).await else {
+        panic!("node b did not accept a stream over the reused connection");
+    };
+    let len = b_from_a_again.read(&mut buffer[..]).await?;
+    assert_eq!(b"AGAIN"[..], buffer[..len]);
+    assert!(timeout(Duration::from_millis(100), rx_b.recv()).await.is_err());
+
     // assert node A informs when node B disconnects
-    drop(proxy_to_a);
+    manager_b.disconnect(&metadata_a.id);
     let Ok(Some(P2pEvent::PeerDisconnected(disconnect_id))) = timeout(Duration::from_millis(100), rx_a.recv()).await else {
         panic!("node a did not recieve disconnect event");
     };
@@ -114,3 +160,76 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn simultaneous_connects_keep_one_connection() -> Result<(), Box<dyn Error>> {
+    let shared_secret = b"123ABCThisIsSuperSecretShhhh!";
+    let auth_a = PairingAuthenticator::new(shared_secret.to_vec())?;
+    let auth_b = PairingAuthenticator::new(shared_secret.to_vec())?;
+
+    let config = P2pConfig {
+        identity: create_identity(),
+        device: p2p::peer::DeviceType::Windows10Desktop,
+        name: String::from("Tester's laptop"),
+        multicast: create_multicast_addr(),
+        p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
+    };
+    let (manager_a, _rx_a) = P2pManager::new(config).await?;
+    let config = P2pConfig {
+        identity: create_identity(),
+        device: p2p::peer::DeviceType::AppleiPhone,
+        name: String::from("Tester's phone"),
+        multicast: create_multicast_addr(),
+        p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
+    };
+    let (manager_b, _rx_b) = P2pManager::new(config).await?;
+
+    let a = manager_a.get_metadata();
+    let b = manager_b.get_metadata();
+    let mut candidate_b = PeerCandidate::new(b, auth_b);
+    candidate_b.addrs.insert(b.addr);
+    let mut candidate_a = PeerCandidate::new(a, auth_a);
+    candidate_a.addrs.insert(a.addr);
+    manager_a.add_known_peer(candidate_b);
+    manager_b.add_known_peer(candidate_a);
+
+    // both nodes dial each other at the same time
+    let (a_to_b, b_to_a) = tokio::join!(
+        timeout(Duration::from_millis(10000), manager_a.connect_to_peer(&b.id)),
+        timeout(Duration::from_millis(10000), manager_b.connect_to_peer(&a.id)),
+    );
+    a_to_b??;
+    b_to_a??;
+    sleep(Duration::from_millis(200)).await;
+    assert!(manager_a.is_connected(&b.id));
+    assert!(manager_b.is_connected(&a.id));
+
+    // assert both nodes kept the connection dialed by the lower id
+    let proxy_to_b = manager_a.connect_to_peer(&b.id).await?;
+    let proxy_to_a = manager_b.connect_to_peer(&a.id).await?;
+    let a_dialed = if a.id < b.id {
+        ConnectionType::Client
+    } else {
+        ConnectionType::Server
+    };
+    assert_eq!(a_dialed, proxy_to_b.conn_type);
+    assert_ne!(proxy_to_b.conn_type, proxy_to_a.conn_type);
+
+    // assert the kept connection carries streams
+    let mut buffer: [u8; 10] = [0; 10];
+    let mut a_to_b = proxy_to_b.open_stream().await?;
+    a_to_b.write_all(b"PING").await?;
+    let Ok(Some(mut b_from_a)) = timeout(Duration::from_millis(1000), proxy_to_a.accept_stream()).await else {
+        panic!("node b did not accept a stream over the kept connection");
+    };
+    let len = b_from_a.read(&mut buffer[..]).await?;
+    assert_eq!(b"PING"[..], buffer[..len]);
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::{error::Error, time::Duration};
+
+use p2p::{
+    err::ConnError,
+    event::P2pEvent,
+    manager::{P2pConfig, P2pManager},
+    pairing::generate_code,
+};
+use tokio::time::sleep;
+
+use crate::common::*;
+
+mod common;
+
+fn create_config(name: &str) -> P2pConfig {
+    P2pConfig {
+        identity: create_identity(),
+        device: p2p::peer::DeviceType::LinuxDevice,
+        name: String::from(name),
+        multicast: create_multicast_addr(),
+        p2p_addr: create_p2p_addr(),
+        idle_timeout: Duration::from_secs(60),
+        max_connections: 16,
+        peer_ttl: Duration::from_secs(60),
+    }
+}
+
+#[tokio::test]
+async fn nearby_peers_pair_over_the_network() -> Result<(), Box<dyn Error>> {
+    let (manager_a, mut rx_a) = P2pManager::new(create_config("Tester's laptop")).await?;
+    let (manager_b, mut rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    let a = manager_a.get_metadata();
+    let b = manager_b.get_metadata();
+
+    // node A finds node B nearby
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let Some(P2pEvent::NearbyDiscovered(_)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not find node b nearby");
+    };
+
+    // node B rejects the first request
+    let request = tokio::spawn({
+        let manager_a = manager_a.clone();
+        let id = b.id.clone();
+        async move { manager_a.request_pairing(&id).await }
+    });
+    let Some(P2pEvent::PairingRequested(req)) = recv_about(&mut rx_b, &a.id, Duration::from_millis(1000)).await else {
+        panic!("node b did not receive the pairing request");
+    };
+    assert_eq!(a.clone(), req.peer);
+    assert!(!req.code);
+    req.reject();
+    assert!(matches!(request.await?, Err(ConnError::Rejected)));
+
+    // node B accepts the second request
+    let request = tokio::spawn({
+        let manager_a = manager_a.clone();
+        let id = b.id.clone();
+        async move { manager_a.request_pairing(&id).await }
+    });
+    let Some(P2pEvent::PairingRequested(req)) = recv_about(&mut rx_b, &a.id, Duration::from_millis(1000)).await else {
+        panic!("node b did not receive the pairing request");
+    };
+    req.accept();
+    request.await??;
+
+    // both nodes share the secret
+    let Some(P2pEvent::PeerPaired { peer: paired_b, secret: secret_a }) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not pair with node b");
+    };
+    let Some(P2pEvent::PeerPaired { peer: paired_a, secret: secret_b }) = recv_about(&mut rx_b, &a.id, Duration::from_millis(500)).await else {
+        panic!("node b did not pair with node a");
+    };
+    assert_eq!(b.clone(), paired_b);
+    assert_eq!(a.clone(), paired_a);
+    assert_eq!(secret_a, secret_b);
+    assert!(manager_a.get_nearby_peers().is_empty());
+
+    // the paired nodes can discover & connect to each other
+    manager_a.request_presence();
+    let Some(P2pEvent::PeerDiscovered(_)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not discover node b");
+    };
+    manager_a.connect_to_peer(&b.id).await?;
+    assert!(manager_a.is_connected(&b.id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn nearby_peers_pair_with_a_code() -> Result<(), Box<dyn Error>> {
+    let (manager_a, mut rx_a) = P2pManager::new(create_config("Tester's laptop")).await?;
+    let (manager_b, mut rx_b) = P2pManager::new(create_config("Tester's phone")).await?;
+    let a = manager_a.get_metadata();
+    let b = manager_b.get_metadata();
+
+    // node A finds node B nearby
+    sleep(Duration::from_millis(100)).await;
+    manager_a.request_presence();
+    let Some(P2pEvent::NearbyDiscovered(_)) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not find node b nearby");
+    };
+
+    // node B's user enters the wrong code
+    let code = generate_code();
+    let request = tokio::spawn({
+        let (manager_a, id, code) = (manager_a.clone(), b.id.clone(), code.clone());
+        async move { manager_a.request_pairing_with_code(&id, &code).await }
+    });
+    let Some(P2pEvent::PairingRequested(req)) = recv_about(&mut rx_b, &a.id, Duration::from_millis(1000)).await else {
+        panic!("node b did not receive the pairing request");
+    };
+    assert!(req.code);
+    let wrong = format!("{:06}", (code.parse::<u32>()? + 1) % 1_000_000);
+    req.accept_with_code(wrong);
+    assert!(matches!(request.await?, Err(ConnError::Auth)));
+    assert!(manager_a.get_nearby_peers().contains(b));
+
+    // node B's user enters the code shown on node A
+    let request = tokio::spawn({
+        let (manager_a, id, code) = (manager_a.clone(), b.id.clone(), code.clone());
+        async move { manager_a.request_pairing_with_code(&id, &code).await }
+    });
+    let Some(P2pEvent::PairingRequested(req)) = recv_about(&mut rx_b, &a.id, Duration::from_millis(1000)).await else {
+        panic!("node b did not receive the pairing request");
+    };
+    req.accept_with_code(code);
+    request.await??;
+
+    // both nodes derived the same secret
+    let Some(P2pEvent::PeerPaired { peer: paired_b, secret: secret_a }) = recv_about(&mut rx_a, &b.id, Duration::from_millis(500)).await else {
+        panic!("node a did not pair with node b");
+    };
+    let Some(P2pEvent::PeerPaired { peer: paired_a, secret: secret_b }) = recv_about(&mut rx_b, &a.id, Duration::from_millis(500)).await else {
+        panic!("node b did not pair with node a");
+    };
+    assert_eq!(b.clone(), paired_b);
+    assert_eq!(a.clone(), paired_a);
+    assert_eq!(secret_a, secret_b);
+
+    Ok(())
+}